@@ -0,0 +1,203 @@
+"use strict";
+
+// How often the archive list and live playlists are reloaded, in ms
+const REFRESH_INTERVAL = 5000;
+
+let archives = [];
+let player = null;
+
+function $(id) {
+  return document.getElementById(id);
+}
+
+// URL of a path relative to the served directory
+function pathUrl(path) {
+  const encoded = path.split("/").map(encodeURIComponent).join("/");
+  return new URL("/" + encoded, location.href);
+}
+
+async function fetchText(url) {
+  const resp = await fetch(url, { cache: "no-store" });
+  if (!resp.ok) {
+    throw new Error(`Could not load ${url}: ${resp.status}`);
+  }
+  return resp.text();
+}
+
+function formatDuration(secs) {
+  secs = Math.floor(secs);
+  const h = Math.floor(secs / 3600);
+  const m = Math.floor(secs / 60) % 60;
+  const s = String(secs % 60).padStart(2, "0");
+  return h ? `${h}:${String(m).padStart(2, "0")}:${s}` : `${m}:${s}`;
+}
+
+function status(archive) {
+  if (!archive.playlist) {
+    return "Video";
+  }
+  return archive.progress && archive.progress.finished ? "Finished" : "Recording";
+}
+
+function describeProgress(archive) {
+  const progress = archive.progress;
+  if (!progress) {
+    return "";
+  }
+  let text = `${progress.segments} segments, ${formatDuration(progress.duration)}`;
+  if (archive.gaps > 0) {
+    text += `, ${archive.gaps} gaps`;
+  }
+  return text;
+}
+
+function cell(row, content) {
+  const td = document.createElement("td");
+  if (content instanceof Node) {
+    td.append(content);
+  } else {
+    td.textContent = content ?? "";
+  }
+  row.append(td);
+  return td;
+}
+
+function renderList() {
+  const body = $("archives");
+  body.replaceChildren();
+  for (const archive of archives) {
+    const row = document.createElement("tr");
+
+    const thumbnail = document.createElement("img");
+    if (archive.thumbnail) {
+      thumbnail.src = pathUrl(archive.path ? `${archive.path}/thumbnail.jpg` : "thumbnail.jpg");
+      thumbnail.loading = "lazy";
+    }
+    cell(row, thumbnail);
+
+    if (archive.playlist) {
+      const link = document.createElement("a");
+      link.href = "#/" + archive.path;
+      link.textContent = archive.title;
+      cell(row, link);
+    } else {
+      cell(row, archive.title);
+    }
+
+    cell(row, archive.channel);
+    const started = archive.start_time && new Date(archive.start_time).toLocaleString();
+    cell(row, started);
+    const state = cell(row, status(archive));
+    state.classList.toggle("recording", status(archive) === "Recording");
+    cell(row, describeProgress(archive));
+    body.append(row);
+  }
+  $("empty").hidden = archives.length > 0;
+}
+
+function renderInfo(archive) {
+  document.title = archive.title;
+  $("title").textContent = archive.title;
+  $("channel").textContent = archive.channel;
+  $("progress").textContent = `${status(archive)} – ${describeProgress(archive)}`;
+}
+
+function showError(message) {
+  $("error").textContent = message;
+  $("error").hidden = false;
+}
+
+// Plays a stream that may still be downloading with hls.js, or the
+// browser's own HLS support where hls.js is not available.
+class Player {
+  constructor(video, archive) {
+    this.video = video;
+    this.archive = archive;
+    this.hls = null;
+  }
+
+  async start() {
+    const masterUrl = pathUrl(this.archive.playlist);
+    if (window.Hls && Hls.isSupported()) {
+      // Recordings are watched from the start rather than the live edge
+      this.hls = new Hls({ startPosition: 0 });
+      this.hls.on(Hls.Events.ERROR, (_, data) => {
+        if (data.fatal) {
+          showError(`Playback failed: ${data.details}`);
+        }
+      });
+      this.hls.loadSource(masterUrl.href);
+      this.hls.attachMedia(this.video);
+    } else if (this.video.canPlayType("application/vnd.apple.mpegurl")) {
+      this.video.src = masterUrl;
+    } else {
+      const link = document.createElement("a");
+      link.href = masterUrl;
+      link.textContent = "playlist";
+      showError("This browser cannot play this stream. Open the playlist in a player such as mpv or VLC: ");
+      $("error").append(link);
+    }
+  }
+
+  stop() {
+    if (this.hls) {
+      this.hls.destroy();
+      this.hls = null;
+    }
+    this.video.removeAttribute("src");
+    this.video.load();
+  }
+}
+
+function route() {
+  const hash = decodeURIComponent(location.hash);
+  const path = hash.startsWith("#/") ? hash.slice(2) : null;
+  // A single archive, such as a served working directory, is opened
+  // directly
+  const archive =
+    path !== null ? archives.find((a) => a.path === path) : archives.length === 1 ? archives[0] : null;
+
+  $("list").hidden = !!archive;
+  $("viewer").hidden = !archive;
+  if (!archive) {
+    if (player) {
+      player.stop();
+      player = null;
+    }
+    document.title = "yta-rs";
+    renderList();
+    return;
+  }
+
+  renderInfo(archive);
+  if (!player || player.archive.path !== archive.path) {
+    if (player) {
+      player.stop();
+    }
+    $("error").hidden = true;
+    player = new Player($("video"), archive);
+    player.start().catch((e) => showError(e.message));
+  }
+}
+
+async function refresh() {
+  try {
+    archives = JSON.parse(await fetchText("/_yta/archives"));
+  } catch (e) {
+    showError(e.message);
+    return;
+  }
+  if (player) {
+    const archive = archives.find((a) => a.path === player.archive.path);
+    if (archive) {
+      renderInfo(archive);
+    }
+  }
+  if (!player) {
+    route();
+  }
+}
+
+window.addEventListener("hashchange", route);
+refresh();
+setInterval(refresh, REFRESH_INTERVAL);
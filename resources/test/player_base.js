@@ -0,0 +1,18 @@
+var _yt_player={};(function(g){var window=this;/*
+
+ Trimmed copy of a YouTube player (base.js) keeping the signature and n
+ parameter transforms, the code that calls them, and some unrelated code
+ around them.
+*/
+'use strict';var ba,ha,ka,Pqa,Wsa;ba=function(a){var b=0;return function(){return b<a.length?{done:!1,value:a[b++]}:{done:!0}}};
+ha=function(a,b){if(b)a:{var c=window;a=a.split(".");for(var d=0;d<a.length-1;d++){var e=a[d];if(!(e in c))break a;c=c[e]}a=a[a.length-1];d=c[a];b=b(d);b!=d&&b!=null&&Object.defineProperty(c,a,{configurable:!0,writable:!0,value:b})}};
+var Xy={Kx:function(a){a.reverse()},
+Nf:function(a,b){a.splice(0,b)},
+oI:function(a,b){var c=a[0];a[0]=a[b%a.length];a[b%a.length]=c}};
+ka=function(a){return a.replace(/[{}]/g,"")};
+Pqa=function(a){a=a.split("");Xy.oI(a,47);Xy.Kx(a,17);Xy.Nf(a,3);Xy.oI(a,12);Xy.Kx(a,6);Xy.Nf(a,1);return a.join("")};
+Wsa=function(a){var b=a.split(""),c=[function(d,e){e=(e%d.length+d.length)%d.length;d.splice(e,1)},-1548012131,"}{",/[}{]/g,function(d){d.reverse()},function(d,e){e=(e%d.length+d.length)%d.length;var f=d[0];d[0]=d[e];d[e]=f},function(d,e){for(e=(e%d.length+d.length)%d.length;e--;)d.unshift(d.pop())},'}',function(){for(var d=64,e=[];++d-e.length-32;){switch(d){case 58:d-=14;case 91:case 92:case 93:continue;case 123:d=47;case 94:case 95:case 96:continue;case 46:d=95;default:e.push(String.fromCharCode(d))}}return e},function(d,e){for(var f=c[8](),h=0;h<d.length;h++)d[h]=f[(f.indexOf(d[h])-f.indexOf(e[h%e.length])+h+f.length)%f.length]},"Hw3T"];c[4](b);c[5](b,c[1]);c[6](b,7);c[9](b,c[10]);c[0](b,3);c[5](b,-28);return b.join("")};
+var Ana=[Wsa];
+g.Ek=function(a,b,c){b=void 0===b?"":b;c=c&&c.sp||"signature";a.set(c,Pqa(b));return a};
+g.Fk=function(a){var b;a.D&&(b=a.get("n"))&&(b=Ana[0](b),a.set("n",b),Ana.length||Wsa(""))};
+})(_yt_player);
@@ -0,0 +1,208 @@
+use std::fmt;
+
+use regex::Regex;
+
+use crate::{player_response::InitialPlayerResponse, util};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ChannelError {
+    #[error("Could not fetch channel page")]
+    DownloadError(#[from] util::DownloadError),
+    #[error("Could not find the ID of {0}")]
+    MissingId(Channel),
+}
+
+/// A channel, identified by its ID (`UC...`) or its handle (`@...`)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum Channel {
+    Id(String),
+    Handle(String),
+}
+
+impl Channel {
+    /// Parses a channel URL such as `https://www.youtube.com/@handle/live`,
+    /// or a bare channel ID or handle.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let path = match url::Url::parse(s) {
+            Ok(url) => {
+                let host = url.host_str()?;
+                if host != "youtube.com" && !host.ends_with(".youtube.com") {
+                    return None;
+                }
+                url.path().to_string()
+            }
+            Err(_) => s.to_string(),
+        };
+
+        let mut parts = path.split('/').filter(|p| !p.is_empty());
+        let channel = match parts.next()? {
+            "channel" => Self::Id(parts.next()?.to_string()),
+            handle if handle.starts_with('@') => Self::Handle(handle.to_string()),
+            id if id.starts_with("UC") && id.len() == 24 => Self::Id(id.to_string()),
+            _ => return None,
+        };
+
+        // Only the channel root and its live page are accepted
+        match parts.next() {
+            None | Some("live") | Some("streams") => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// URL that shows the current live stream of the channel
+    pub fn live_url(&self) -> String {
+        format!("https://www.youtube.com/{}/live", self)
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::Id(id) => write!(f, "channel/{}", id),
+            Channel::Handle(handle) => write!(f, "{}", handle),
+        }
+    }
+}
+
+impl TryFrom<String> for Channel {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::parse(&s).ok_or_else(|| format!("Invalid channel {:?}", s))
+    }
+}
+
+/// Extracts the live or upcoming video from the HTML of a channel's live
+/// page. Channels without a live stream show the channel page instead, or
+/// sometimes a stream that has already ended.
+pub fn live_from_html(html: &str) -> Option<(String, InitialPlayerResponse)> {
+    let ipr = InitialPlayerResponse::from_html(html).ok()?;
+    let details = ipr.video_details.as_ref()?;
+    if !details.is_live && !details.is_upcoming {
+        return None;
+    }
+
+    Some((details.video_id.clone(), ipr))
+}
+
+/// Extracts the channel ID from the HTML of a channel page.
+pub fn id_from_html(html: &str) -> Option<String> {
+    let re = Regex::new(
+        r#"<link rel="canonical" href="https://www\.youtube\.com/channel/(UC[\w-]{22})"|"externalId":"(UC[\w-]{22})""#,
+    )
+    .ok()?;
+    let captures = re.captures(html)?;
+    let id = captures.get(1).or_else(|| captures.get(2))?;
+    Some(id.as_str().to_string())
+}
+
+/// Returns the ID of a channel, fetching the channel page of a handle to
+/// look it up.
+pub async fn resolve_id(
+    client: &util::HttpClient,
+    channel: &Channel,
+) -> Result<String, ChannelError> {
+    match channel {
+        Channel::Id(id) => Ok(id.clone()),
+        Channel::Handle(handle) => {
+            let html = client
+                .fetch_text(&format!("https://www.youtube.com/{}", handle))
+                .await?;
+            id_from_html(&html).ok_or_else(|| ChannelError::MissingId(channel.clone()))
+        }
+    }
+}
+
+/// Finds the current live or upcoming stream of a channel, returning its
+/// video ID and player response.
+pub async fn find_live(
+    client: &util::HttpClient,
+    channel: &Channel,
+) -> Result<Option<(String, InitialPlayerResponse)>, ChannelError> {
+    let html = client.fetch_text(&channel.live_url()).await?;
+    Ok(live_from_html(&html))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_html(fname: &str) -> String {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/");
+        d.push(fname);
+        std::fs::read_to_string(d).unwrap_or_else(|_| panic!("Could not read {}", fname))
+    }
+
+    #[test]
+    fn parse_channel() {
+        let id = Channel::Id("UC1opHUrw8rvnsadT-iGp7Cg".to_string());
+        let handle = Channel::Handle("@MinatoAqua".to_string());
+
+        assert_eq!(
+            Channel::parse("https://www.youtube.com/channel/UC1opHUrw8rvnsadT-iGp7Cg/live"),
+            Some(id.clone())
+        );
+        assert_eq!(
+            Channel::parse("https://youtube.com/channel/UC1opHUrw8rvnsadT-iGp7Cg"),
+            Some(id.clone())
+        );
+        assert_eq!(Channel::parse("UC1opHUrw8rvnsadT-iGp7Cg"), Some(id.clone()));
+        assert_eq!(
+            Channel::parse("https://www.youtube.com/@MinatoAqua/live"),
+            Some(handle.clone())
+        );
+        assert_eq!(Channel::parse("@MinatoAqua"), Some(handle.clone()));
+
+        assert_eq!(
+            Channel::parse("https://www.youtube.com/watch?v=jfKfPfyJRdk"),
+            None
+        );
+        assert_eq!(Channel::parse("https://example.com/@MinatoAqua"), None);
+        assert_eq!(
+            Channel::parse("https://www.youtube.com/@MinatoAqua/videos"),
+            None
+        );
+
+        assert_eq!(
+            id.live_url(),
+            "https://www.youtube.com/channel/UC1opHUrw8rvnsadT-iGp7Cg/live"
+        );
+        assert_eq!(
+            handle.live_url(),
+            "https://www.youtube.com/@MinatoAqua/live"
+        );
+    }
+
+    #[test]
+    fn live_page() {
+        let (video_id, _) = live_from_html(&get_test_html("watchpage_live.html")).unwrap();
+        assert_eq!(video_id, "jfKfPfyJRdk");
+
+        let (video_id, _) = live_from_html(&get_test_html("watchpage_scheduled.html")).unwrap();
+        assert_eq!(video_id, "Fl1vM3scybw");
+
+        assert!(live_from_html(&get_test_html("watchpage_post_live.html")).is_none());
+        assert!(live_from_html("<html></html>").is_none());
+    }
+
+    #[test]
+    fn channel_id() {
+        let id = "UC1opHUrw8rvnsadT-iGp7Cg";
+        let html = format!(
+            r#"<head><link rel="canonical" href="https://www.youtube.com/channel/{}"></head>"#,
+            id
+        );
+        assert_eq!(id_from_html(&html).as_deref(), Some(id));
+        let html = format!(
+            r#"{{"metadata":{{"channelMetadataRenderer":{{"externalId":"{}"}}}}}}"#,
+            id
+        );
+        assert_eq!(id_from_html(&html).as_deref(), Some(id));
+
+        // Watch pages link to the video instead
+        assert!(id_from_html(&get_test_html("watchpage_live.html")).is_none());
+    }
+}
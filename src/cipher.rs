@@ -0,0 +1,477 @@
+use std::{collections::HashMap, path::Path};
+
+use regex::Regex;
+use rquickjs::{CatchResultExt, Context, Function, Runtime};
+
+use crate::{player_response::AdaptiveFormat, util};
+
+#[derive(thiserror::Error, Debug)]
+pub enum CipherError {
+    #[error("Invalid player URL: {0}")]
+    InvalidPlayerUrl(String),
+    #[error("Could not download player")]
+    DownloadError(#[from] util::DownloadError),
+    #[error("I/O error")]
+    IoError(#[from] std::io::Error),
+    #[error("Could not find {0} in player")]
+    MissingFunction(&'static str),
+    #[error("JavaScript error: {0}")]
+    JsError(String),
+    #[error("Invalid signature cipher")]
+    InvalidCipher,
+    #[error("Invalid URL")]
+    InvalidUrl(#[from] url::ParseError),
+}
+
+impl From<rquickjs::Error> for CipherError {
+    fn from(e: rquickjs::Error) -> Self {
+        CipherError::JsError(e.to_string())
+    }
+}
+
+/// Finds the player JS URL in a watch page.
+pub fn player_url(html: &str) -> Option<String> {
+    let re = Regex::new(r#""jsUrl":"(/s/player/[^"]+\.js)""#).ok()?;
+    let path = re.captures(html)?.get(1)?.as_str();
+    Some(format!("https://www.youtube.com{}", path))
+}
+
+/// The player version, e.g. `bbe1b497` for `/s/player/bbe1b497/.../base.js`
+fn player_id(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("/s/player/")?;
+    rest.split('/')
+        .next()
+        .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// Returns the code of the block starting at `start`, which must point at a
+/// `{`. Strings and regex literals are skipped, as they often contain braces.
+fn extract_block(js: &str, start: usize) -> Option<&str> {
+    let bytes = js.as_bytes();
+    if bytes.get(start) != Some(&b'{') {
+        return None;
+    }
+
+    let mut depth = 0;
+    let mut i = start;
+    let mut prev = b'{';
+    while i < bytes.len() {
+        let c = bytes[i];
+        match c {
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&js[start..=i]);
+                }
+            }
+            b'"' | b'\'' | b'`' => i = skip_literal(bytes, i, c)?,
+            // A slash after an operator starts a regex, not a division
+            b'/' if b"(,=:[!&|?{};".contains(&prev) => i = skip_literal(bytes, i, b'/')?,
+            _ => (),
+        }
+        if !c.is_ascii_whitespace() {
+            prev = c;
+        }
+        i += 1;
+    }
+
+    None
+}
+
+/// Returns the index of the unescaped `end` closing the literal at `start`.
+fn skip_literal(bytes: &[u8], start: usize, end: u8) -> Option<usize> {
+    let mut i = start + 1;
+    let mut in_class = false;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'[' if end == b'/' => in_class = true,
+            b']' if end == b'/' => in_class = false,
+            c if c == end && !in_class => return Some(i),
+            _ => (),
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Finds `name=function(...){...}` and returns `function(...){...}`.
+fn extract_function<'a>(js: &'a str, name: &str) -> Option<&'a str> {
+    let re = Regex::new(&format!(
+        r"(?:^|[^a-zA-Z0-9_$.]){}\s*=\s*function\([^)]*\)",
+        regex::escape(name)
+    ))
+    .ok()?;
+    let m = re.find(js)?;
+    let start = m.start() + js[m.start()..].find("function")?;
+    let body = extract_block(js, m.end())?;
+    Some(&js[start..m.end() + body.len()])
+}
+
+/// The signature and n parameter transforms extracted from a player
+#[derive(Debug, Clone)]
+pub struct Player {
+    sig_code: String,
+    n_code: Option<String>,
+}
+
+impl Player {
+    /// Extracts the transforms from the player JS.
+    pub fn from_js(js: &str) -> Result<Self, CipherError> {
+        Ok(Self {
+            sig_code: extract_sig_code(js).ok_or(CipherError::MissingFunction("signature"))?,
+            n_code: extract_n_code(js),
+        })
+    }
+
+    /// Loads the player from `cache_dir`, downloading it if it is not cached.
+    pub async fn fetch(
+        client: &util::HttpClient,
+        url: &str,
+        cache_dir: &Path,
+    ) -> Result<Self, CipherError> {
+        let id = player_id(url).ok_or_else(|| CipherError::InvalidPlayerUrl(url.to_string()))?;
+        let path = cache_dir.join(format!("player_{}.js", id));
+
+        let js = match tokio::fs::read_to_string(&path).await {
+            Ok(js) => js,
+            Err(_) => {
+                info!("Downloading player {}", id);
+                let js = client.fetch_text(url).await?;
+                tokio::fs::create_dir_all(cache_dir).await?;
+                tokio::fs::write(&path, &js).await?;
+                js
+            }
+        };
+
+        Self::from_js(&js)
+    }
+
+    /// Replaces the URL of every format with a usable one, deciphering the
+    /// signature and transforming the n parameter. Formats whose signature
+    /// cannot be deciphered are left without a URL. If only the n transform
+    /// fails, the URL still works but is throttled, so it is kept. Returns
+    /// the number of formats with a URL.
+    pub fn decipher_formats(&self, formats: &mut [AdaptiveFormat]) -> Result<usize, CipherError> {
+        let mut js = Js::new(self)?;
+        let mut count = 0;
+
+        for format in formats.iter_mut() {
+            let url = match js.signed_url(format.url.as_deref(), format.signature_cipher.as_deref())
+            {
+                Ok(url) => url,
+                Err(e) => {
+                    warn!("Could not decipher URL of f{}: {}", format.itag, e);
+                    format.url = None;
+                    continue;
+                }
+            };
+            let url = match js.transform_n(url.clone()) {
+                Ok(url) => url,
+                Err(e) => {
+                    warn!(
+                        "Could not transform n of f{}, it may be throttled: {}",
+                        format.itag, e
+                    );
+                    url.into()
+                }
+            };
+            format.url = Some(url);
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Returns a usable URL from a format's `url` or `signatureCipher`.
+    pub fn decipher_url(
+        &self,
+        url: Option<&str>,
+        signature_cipher: Option<&str>,
+    ) -> Result<String, CipherError> {
+        Js::new(self)?.decipher_url(url, signature_cipher)
+    }
+}
+
+fn extract_sig_code(js: &str) -> Option<String> {
+    // e.g. `Pqa=function(a){a=a.split("");Xy.oI(a,47);...;return a.join("")}`
+    let re = Regex::new(
+        r#"([a-zA-Z0-9_$]+)\s*=\s*function\(\s*([a-zA-Z0-9_$]+)\s*\)\s*\{\s*[a-zA-Z0-9_$]+\s*=\s*[a-zA-Z0-9_$]+\.split\(\s*""\s*\)"#,
+    )
+    .ok()?;
+    let name = re.captures(js)?.get(1)?.as_str();
+    let function = extract_function(js, name)?;
+
+    // The operations are methods of a helper object
+    let re = Regex::new(r";\s*([a-zA-Z0-9_$]+)\.[a-zA-Z0-9_$]+\(").ok()?;
+    let helper_name = re.captures(function)?.get(1)?.as_str();
+    let re = Regex::new(&format!(r"var\s+{}\s*=\s*", regex::escape(helper_name))).ok()?;
+    let helper = extract_block(js, re.find(js)?.end())?;
+
+    Some(format!(
+        "var {}={};var __yta_sig={};",
+        helper_name, helper, function
+    ))
+}
+
+fn extract_n_code(js: &str) -> Option<String> {
+    // e.g. `(b=a.get("n"))&&(b=Ana[0](b),a.set("n",b)`
+    let re = Regex::new(
+        r#"\.get\("n"\)\)&&\([a-zA-Z0-9_$]+=([a-zA-Z0-9_$]+)(?:\[(\d+)\])?\([a-zA-Z0-9_$]+\)"#,
+    )
+    .ok()?;
+    let caps = re.captures(js)?;
+    let mut name = caps.get(1)?.as_str();
+
+    // The function is sometimes referenced through an array
+    if let Some(index) = caps.get(2) {
+        let index: usize = index.as_str().parse().ok()?;
+        let re = Regex::new(&format!(
+            r"var\s+{}\s*=\s*\[([^\]]*)\]",
+            regex::escape(name)
+        ))
+        .ok()?;
+        name = re
+            .captures(js)?
+            .get(1)?
+            .as_str()
+            .split(',')
+            .nth(index)?
+            .trim();
+    }
+
+    Some(format!("var __yta_n={};", extract_function(js, name)?))
+}
+
+/// A JavaScript context with the transforms loaded
+struct Js {
+    context: Context,
+    has_n: bool,
+    n_cache: HashMap<String, String>,
+}
+
+impl Js {
+    fn new(player: &Player) -> Result<Self, CipherError> {
+        let runtime = Runtime::new()?;
+        let context = Context::full(&runtime)?;
+        let code = format!(
+            "{}{}",
+            player.sig_code,
+            player.n_code.as_deref().unwrap_or_default()
+        );
+        context.with(|ctx| {
+            ctx.eval::<(), _>(code)
+                .catch(&ctx)
+                .map_err(|e| CipherError::JsError(e.to_string()))
+        })?;
+
+        Ok(Self {
+            context,
+            has_n: player.n_code.is_some(),
+            n_cache: HashMap::new(),
+        })
+    }
+
+    fn call(&self, function: &str, arg: &str) -> Result<String, CipherError> {
+        self.context.with(|ctx| {
+            let f: Function = ctx.globals().get(function)?;
+            f.call::<_, String>((arg,))
+                .catch(&ctx)
+                .map_err(|e| CipherError::JsError(e.to_string()))
+        })
+    }
+
+    fn signature(&self, s: &str) -> Result<String, CipherError> {
+        self.call("__yta_sig", s)
+    }
+
+    fn n(&mut self, n: &str) -> Result<String, CipherError> {
+        if let Some(result) = self.n_cache.get(n) {
+            return Ok(result.clone());
+        }
+        let result = self.call("__yta_n", n)?;
+        // The transform returns the input prefixed with `enhanced_except_`
+        // when it fails
+        if result.starts_with("enhanced_except_") || result == n {
+            return Err(CipherError::JsError(format!(
+                "n transform failed: {}",
+                result
+            )));
+        }
+        self.n_cache.insert(n.to_string(), result.clone());
+        Ok(result)
+    }
+
+    fn decipher_url(
+        &mut self,
+        url: Option<&str>,
+        signature_cipher: Option<&str>,
+    ) -> Result<String, CipherError> {
+        let url = self.signed_url(url, signature_cipher)?;
+        self.transform_n(url)
+    }
+
+    /// Returns the format's URL, with the signature deciphered if it has a
+    /// `signatureCipher` instead.
+    fn signed_url(
+        &self,
+        url: Option<&str>,
+        signature_cipher: Option<&str>,
+    ) -> Result<url::Url, CipherError> {
+        Ok(match (url, signature_cipher) {
+            (Some(url), _) => url::Url::parse(url)?,
+            (None, Some(cipher)) => {
+                // s=<signature>&sp=<param>&url=<url>
+                let params = url::form_urlencoded::parse(cipher.as_bytes())
+                    .into_owned()
+                    .collect::<HashMap<_, _>>();
+                let s = params.get("s").ok_or(CipherError::InvalidCipher)?;
+                let sp = params.get("sp").map(|s| s.as_str()).unwrap_or("signature");
+                let mut url =
+                    url::Url::parse(params.get("url").ok_or(CipherError::InvalidCipher)?)?;
+                url.query_pairs_mut().append_pair(sp, &self.signature(s)?);
+                url
+            }
+            (None, None) => return Err(CipherError::InvalidCipher),
+        })
+    }
+
+    /// Replaces the n parameter of a URL, which YouTube throttles otherwise.
+    fn transform_n(&mut self, mut url: url::Url) -> Result<String, CipherError> {
+        let n = url
+            .query_pairs()
+            .find(|(k, _)| k == "n")
+            .map(|(_, v)| v.into_owned());
+        if let (Some(n), true) = (n, self.has_n) {
+            let n = self.n(&n)?;
+            let pairs = url
+                .query_pairs()
+                .map(|(k, v)| {
+                    let v = if k == "n" { n.clone() } else { v.into_owned() };
+                    (k.into_owned(), v)
+                })
+                .collect::<Vec<_>>();
+            url.query_pairs_mut().clear().extend_pairs(pairs);
+        }
+
+        Ok(url.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_player() -> Player {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/player_base.js");
+        let js = std::fs::read_to_string(d).expect("Could not read player");
+        Player::from_js(&js).expect("Could not extract transforms")
+    }
+
+    #[test]
+    fn find_player_url() {
+        let html = r#"{"jsUrl":"/s/player/bbe1b497/player_ias.vflset/en_US/base.js","cssUrl":""}"#;
+        let url = player_url(html).unwrap();
+        assert_eq!(
+            url,
+            "https://www.youtube.com/s/player/bbe1b497/player_ias.vflset/en_US/base.js"
+        );
+        assert_eq!(player_id(&url), Some("bbe1b497"));
+    }
+
+    #[test]
+    fn extract_blocks() {
+        let js = r#"x={a:"}",b:/[}]\//g,c:function(){return{}}};"#;
+        assert_eq!(extract_block(js, 2), Some(&js[2..js.len() - 1]));
+        assert_eq!(extract_block(js, 0), None);
+    }
+
+    #[test]
+    fn decipher_signature() {
+        let player = get_player();
+        let url = player
+            .decipher_url(
+                None,
+                Some("s=AOq0QJ8wRAIgM08fV4K6tOxGI3Ueyrg7BZO0BEElK49_QigT2no7VVICIDkZEeXUHj3QdZOdd3iW2d2hD8ziriC6yZnPaAeHLhWdhWo3&sp=sig&url=https://rr5---sn-npoldne7.googlevideo.com/videoplayback%3Fexpire%3D1685658603%26itag%3D251"),
+            )
+            .unwrap();
+        assert_eq!(
+            url,
+            "https://rr5---sn-npoldne7.googlevideo.com/videoplayback?expire=1685658603&itag=251&sig=Oq0QJ8wRAIgM08fV4K6tOxGI3Ueyrg7BZO0BEElK49_QigA2no7VVICIDkZEeXUHj3QdZOdd3iW2d2hD8ziriC6hZnPaAeHLhWdy"
+        );
+    }
+
+    #[test]
+    fn transform_n() {
+        let player = get_player();
+        let url = player
+            .decipher_url(
+                Some("https://rr4---sn-npoeenl7.googlevideo.com/videoplayback?itag=136&n=-5K81c4AQz2p_mWhYl&c=WEB"),
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            url,
+            "https://rr4---sn-npoeenl7.googlevideo.com/videoplayback?itag=136&n=MtAHONxm6peEGNvZh&c=WEB"
+        );
+    }
+
+    /// `player_base.js` is trimmed by hand. Set `YTA_TEST_PLAYER` to a saved
+    /// `base.js` to check the extraction against a whole player.
+    #[test]
+    fn saved_player() {
+        let Some(path) = std::env::var_os("YTA_TEST_PLAYER") else {
+            eprintln!("Skipping saved_player: YTA_TEST_PLAYER is not set");
+            return;
+        };
+        let js = std::fs::read_to_string(path).expect("Could not read player");
+        let player = Player::from_js(&js).expect("Could not extract transforms");
+        assert!(player.n_code.is_some(), "Could not find the n transform");
+
+        let mut js = Js::new(&player).unwrap();
+        let s = "AOq0QJ8wRAIgM08fV4K6tOxGI3Ueyrg7BZO0BEElK49_QigT2no7VVICIDkZEeXUHj3QdZOdd3iW2d2hD8ziriC6yZnPaAeHLhWdhWo3";
+        assert_ne!(js.signature(s).unwrap(), s);
+        js.n("-5K81c4AQz2p_mWhYl").unwrap();
+    }
+
+    #[test]
+    fn failed_n_keeps_url() {
+        // An n transform that fails the way the player's does
+        let player = Player {
+            n_code: Some("var __yta_n=function(a){return \"enhanced_except_\"+a};".to_string()),
+            ..get_player()
+        };
+        let format = |url: Option<&str>, cipher: Option<&str>| {
+            serde_json::from_value::<AdaptiveFormat>(serde_json::json!({
+                "itag": 251,
+                "url": url,
+                "signatureCipher": cipher,
+                "mimeType": "audio/webm; codecs=\"opus\"",
+                "bitrate": 1,
+            }))
+            .unwrap()
+        };
+        let plain = "https://rr4---sn-npoeenl7.googlevideo.com/videoplayback?itag=251&n=abc";
+        let mut formats = vec![
+            format(Some(plain), None),
+            format(
+                None,
+                Some("s=AOq0QJ8wRAIgM08fV4K6tOxGI3Ueyrg7BZO0BEElK49_QigT2no7VVICIDkZEeXUHj3QdZOdd3iW2d2hD8ziriC6yZnPaAeHLhWdhWo3&sp=sig&url=https://rr5---sn-npoldne7.googlevideo.com/videoplayback%3Fitag%3D251%26n%3Dabc"),
+            ),
+            format(None, Some("sp=sig")),
+        ];
+
+        assert_eq!(player.decipher_formats(&mut formats).unwrap(), 2);
+        // The URLs work, but are throttled
+        assert_eq!(formats[0].url.as_deref(), Some(plain));
+        assert!(formats[1]
+            .url
+            .as_deref()
+            .unwrap()
+            .ends_with("n=abc&sig=Oq0QJ8wRAIgM08fV4K6tOxGI3Ueyrg7BZO0BEElK49_QigA2no7VVICIDkZEeXUHj3QdZOdd3iW2d2hD8ziriC6hZnPaAeHLhWdy"));
+        // A signature that cannot be deciphered leaves no URL
+        assert_eq!(formats[2].url, None);
+    }
+}
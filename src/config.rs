@@ -0,0 +1,307 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    monitor::MonitorConfig,
+    template::{self, Template},
+    util::HttpOptions,
+    worker::WorkerConfig,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Could not read config file")]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid config file: {0}")]
+    ParseError(#[from] toml::de::Error),
+    #[error("{0} sets an option inside one that is not a table")]
+    InvalidEnv(String),
+}
+
+/// Prefix of environment variables that override the config file
+const ENV_PREFIX: &str = "YTA_";
+/// Options that can be given as a comma-separated list in the environment
+const LIST_KEYS: &[&str] = &["monitor.channels"];
+
+/// Options read from a TOML config file. Every field is optional and falls
+/// back to the default of its type.
+///
+/// Environment variables override the file, with nested keys separated by
+/// `__`, e.g. `YTA_HTTP__RETRIES=5` or `YTA_MONITOR__CHANNELS=@a,@b`.
+///
+/// ```toml
+/// workdir = "{channel}/{id}"
+/// output = "{channel}/{start_date} {title} [{id}].mp4"
+///
+/// [http]
+/// cookies = "cookies.txt"
+///
+/// [worker]
+/// format = "1080p"
+///
+/// [monitor]
+/// channels = ["@MinatoAqua"]
+/// poll_interval = 120
+/// ```
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Working directory of each stream
+    pub workdir: Template,
+    /// File each stream is muxed to
+    pub output: Template,
+    /// Address to serve Prometheus metrics on
+    pub metrics: Option<SocketAddr>,
+    pub http: HttpOptions,
+    pub worker: WorkerConfig,
+    pub monitor: MonitorConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            workdir: Template::new(template::DEFAULT_WORKDIR).expect("Invalid default template"),
+            output: Template::new(template::DEFAULT_OUTPUT).expect("Invalid default template"),
+            metrics: None,
+            http: HttpOptions::default(),
+            worker: WorkerConfig::default(),
+            monitor: MonitorConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(toml: &str) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    pub async fn load(path: &Path) -> Result<Self, ConfigError> {
+        Self::parse(&tokio::fs::read_to_string(path).await?)
+    }
+
+    /// Parses a config file, then applies the `YTA_<SECTION>__<KEY>`
+    /// variables in `vars` on top of it. Values are read as TOML, falling
+    /// back to a string, so a string that looks like a number has to be
+    /// quoted.
+    pub fn parse_with_env(
+        toml: &str,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut table = toml.parse::<toml::Table>()?;
+        apply_env(&mut table, vars)?;
+        Ok(toml::Value::Table(table).try_into()?)
+    }
+
+    /// Reads the config file at `path`, if any, with overrides from `vars`.
+    pub async fn load_with_env(
+        path: Option<&Path>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let toml = match path {
+            Some(path) => tokio::fs::read_to_string(path).await?,
+            None => String::new(),
+        };
+        Self::parse_with_env(&toml, vars)
+    }
+
+    /// Options for the monitor, using the shared templates and worker
+    /// options.
+    pub fn monitor_config(&self) -> MonitorConfig {
+        MonitorConfig {
+            workdir: self.workdir.clone(),
+            output: self.output.clone(),
+            worker: self.worker.clone(),
+            ..self.monitor.clone()
+        }
+    }
+}
+
+/// Sets the options named by environment variables in a parsed config file.
+/// Only variables with a nested key are used, as the top-level options have
+/// command line flags with their own variables.
+fn apply_env(
+    table: &mut toml::Table,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), ConfigError> {
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX).filter(|k| k.contains("__")) else {
+            continue;
+        };
+        let path = key
+            .to_lowercase()
+            .split("__")
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let (last, sections) = path.split_last().expect("split is never empty");
+
+        let mut section = &mut *table;
+        for name_part in sections {
+            section = section
+                .entry(name_part.as_str())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| ConfigError::InvalidEnv(name.clone()))?;
+        }
+        section.insert(last.clone(), env_value(&path.join("."), &value));
+    }
+    Ok(())
+}
+
+fn env_value(key: &str, value: &str) -> toml::Value {
+    if LIST_KEYS.contains(&key) && !value.trim_start().starts_with('[') {
+        return toml::Value::Array(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(|v| toml::Value::String(v.to_string()))
+                .collect(),
+        );
+    }
+
+    format!("value = {}", value)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut t| t.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+/// Where the config file is read from if none is given, e.g.
+/// `~/.config/yta-rs/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+        .map(|d| d.join("yta-rs").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{channel::Channel, source::FormatSelection, source::SourceKind};
+
+    #[test]
+    fn parse() {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/config.toml");
+        let toml = std::fs::read_to_string(d).expect("Could not read config");
+        let config = Config::parse(&toml).expect("Could not parse config");
+
+        assert_eq!(config.workdir, Template::new("{channel}/{id}").unwrap());
+        assert_eq!(config.metrics, Some(([127, 0, 0, 1], 9090).into()));
+        assert_eq!(
+            config.http.proxy.as_deref(),
+            Some("socks5://127.0.0.1:1080")
+        );
+        assert_eq!(config.http.retries, 5);
+        assert_eq!(config.worker.format, FormatSelection::MaxHeight(1080));
+        assert_eq!(config.worker.source, SourceKind::Hls);
+        assert_eq!(config.worker.concurrency, 4);
+        assert_eq!(config.worker.poll_interval, Duration::from_millis(500));
+        assert_eq!(config.worker.no_segment_timeout, Duration::from_secs(30));
+        assert_eq!(config.worker.retry.attempts, 3);
+        assert_eq!(
+            config.worker.segment_dir,
+            Some(std::path::PathBuf::from("segments"))
+        );
+
+        let monitor = config.monitor_config();
+        assert_eq!(
+            monitor.channels,
+            vec![
+                Channel::Handle("@MinatoAqua".to_string()),
+                Channel::Id("UC1opHUrw8rvnsadT-iGp7Cg".to_string()),
+            ]
+        );
+        assert_eq!(monitor.poll_interval, Duration::from_secs_f64(90.5));
+        assert_eq!(monitor.max_concurrent, 4);
+        assert_eq!(monitor.workdir, config.workdir);
+        assert_eq!(monitor.worker.format, config.worker.format);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(Config::parse("").is_ok());
+        assert!(Config::parse("output = \"{nope}\"").is_err());
+        assert!(Config::parse("[worker]\nformat = \"highest\"").is_err());
+        assert!(Config::parse("[monitor]\nchannels = [\"nope\"]").is_err());
+        assert!(Config::parse("[monitor]\npoll_interval = -1").is_err());
+        assert!(Config::parse("[http]\nproxies = []").is_err());
+        assert!(Config::parse("[worker]\nsegment_dir = \"/tmp\"").is_err());
+        assert!(Config::parse("[worker]\nsegment_dir = \"a/../..\"").is_err());
+        assert!(
+            Config::parse_with_env("", [("YTA_WORKER__SEGMENT_DIR".into(), "..".into())]).is_err()
+        );
+    }
+
+    #[test]
+    fn env_overrides() {
+        let vars = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let toml = "[http]\nretries = 5\n\n[worker]\nplaylist = \"file.m3u8\"";
+
+        // The environment overrides the file, which overrides the defaults
+        let config = Config::parse_with_env(
+            toml,
+            vars(&[
+                ("YTA_HTTP__RETRIES", "7"),
+                ("YTA_WORKER__RETRY__INITIAL_DELAY", "0.5"),
+                ("YTA_WORKER__SEGMENT_DIR", "segments"),
+                ("YTA_WORKER__FORMAT", "\"22\""),
+                (
+                    "YTA_MONITOR__CHANNELS",
+                    "@MinatoAqua, UC1opHUrw8rvnsadT-iGp7Cg",
+                ),
+                ("YTA_MONITOR__MAX_CONCURRENT", "2"),
+                // Handled by command line flags
+                ("YTA_PROXY", "socks5://127.0.0.1:1080"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .expect("Could not parse config");
+        assert_eq!(config.http.retries, 7);
+        assert_eq!(config.http.proxy, None);
+        assert_eq!(config.worker.playlist, "file.m3u8");
+        assert_eq!(
+            config.worker.retry.initial_delay,
+            Duration::from_millis(500)
+        );
+        assert_eq!(config.worker.retry.attempts, 5);
+        assert_eq!(
+            config.worker.segment_dir,
+            Some(std::path::PathBuf::from("segments"))
+        );
+        assert_eq!(config.worker.format, FormatSelection::Itags(vec![22]));
+        assert_eq!(
+            config.monitor.channels,
+            vec![
+                Channel::Handle("@MinatoAqua".to_string()),
+                Channel::Id("UC1opHUrw8rvnsadT-iGp7Cg".to_string()),
+            ]
+        );
+        assert_eq!(config.monitor.max_concurrent, 2);
+
+        let config = Config::parse_with_env(toml, vars(&[("YTA_WORKER__PLAYLIST", "env.m3u8")]))
+            .expect("Could not parse config");
+        assert_eq!(config.http.retries, 5);
+        assert_eq!(config.worker.playlist, "env.m3u8");
+
+        assert!(Config::parse_with_env("", vars(&[("YTA_HTTP__RETRIES", "many")])).is_err());
+        assert!(Config::parse_with_env("", vars(&[("YTA_HTTP__PROXIES", "x")])).is_err());
+        assert!(matches!(
+            Config::parse_with_env(
+                "metrics = \"127.0.0.1:9090\"",
+                vars(&[("YTA_METRICS__PORT", "1")])
+            ),
+            Err(ConfigError::InvalidEnv(_))
+        ));
+    }
+}
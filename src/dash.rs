@@ -1,3 +1,4 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
 use quick_xml::{events::Event, Reader};
 use std::str::FromStr;
 
@@ -5,9 +6,52 @@ use std::str::FromStr;
 pub struct Manifest {
     pub segment_duration: f64,
     pub latest_segment_number: i64,
+    /// Number of the first segment listed in the manifest
+    pub start_number: i64,
+    /// Wall-clock time at which the first listed segment starts
+    pub start_time: Option<DateTime<Utc>>,
     pub representations: Vec<Representation>,
 }
 
+impl Manifest {
+    /// Wall-clock time at which a segment starts. Segments before the ones
+    /// listed are assumed to have the same duration.
+    pub fn segment_time(&self, seq: i64) -> Option<DateTime<Utc>> {
+        let offset = (seq - self.start_number) as f64 * self.segment_duration;
+        Some(self.start_time? + chrono::Duration::milliseconds(offset as i64))
+    }
+}
+
+/// Parses a date time without a time zone, such as `availabilityStartTime`,
+/// as UTC.
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    match DateTime::parse_from_rfc3339(time) {
+        Ok(time) => Some(time.with_timezone(&Utc)),
+        Err(_) => NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f")
+            .ok()
+            .map(|t| t.and_utc()),
+    }
+}
+
+/// Parses the time part of an `xs:duration`, e.g. `PT1H2M3.5S`.
+fn parse_duration(duration: &str) -> Option<chrono::Duration> {
+    let mut rest = duration.strip_prefix("PT")?;
+    let mut secs = 0.0;
+    while !rest.is_empty() {
+        let end = rest.find(|c: char| c.is_ascii_alphabetic())?;
+        let value: f64 = rest[..end].parse().ok()?;
+        secs += value
+            * match &rest[end..end + 1] {
+                "H" => 3600.0,
+                "M" => 60.0,
+                "S" => 1.0,
+                _ => return None,
+            };
+        rest = &rest[end + 1..];
+    }
+    Some(chrono::Duration::milliseconds((secs * 1000.0) as i64))
+}
+
 #[derive(Debug)]
 pub struct Representation {
     pub id: i64,
@@ -49,24 +93,15 @@ impl Representation {
         loop {
             match reader.read_event() {
                 Err(e) => return Err(e),
-                Ok(Event::Start(e)) => match e.name().as_ref() {
-                    b"BaseURL" => is_base_url_tag = true,
-                    _ => (),
-                },
-                Ok(Event::Text(e)) => {
-                    if is_base_url_tag {
-                        repr.base_url = e
-                            .unescape()
-                            .ok()
-                            .map(|u| u.into_owned())
-                            .unwrap_or_default();
-                    }
-                }
-                Ok(Event::End(e)) => {
-                    if e.name().as_ref() == b"Representation" {
-                        break;
-                    }
+                Ok(Event::Start(e)) if e.name().as_ref() == b"BaseURL" => is_base_url_tag = true,
+                Ok(Event::Text(e)) if is_base_url_tag => {
+                    repr.base_url = e
+                        .unescape()
+                        .ok()
+                        .map(|u| u.into_owned())
+                        .unwrap_or_default();
                 }
+                Ok(Event::End(e)) if e.name().as_ref() == b"Representation" => break,
                 _ => (),
             }
         }
@@ -86,25 +121,38 @@ pub fn parse_manifest(manifest: &str) -> Result<Manifest, quick_xml::Error> {
     let mut m = Manifest {
         segment_duration: 0.0,
         latest_segment_number: 0,
+        start_number: 0,
+        start_time: None,
         representations: Vec::new(),
     };
+    let mut availability_start = None;
 
     loop {
         match reader.read_event() {
             Err(e) => return Err(e),
             Ok(Event::Eof) => break,
-            Ok(Event::Empty(e)) => match e.name().as_ref() {
-                b"S" => {
-                    m.segment_duration = get_attr(&e, "d").ok_or(quick_xml::Error::TextNotFound)?;
-                    m.latest_segment_number += 1;
-                }
-                _ => (),
-            },
+            // Accept either case of the timeline entries
+            Ok(Event::Empty(e)) if matches!(e.name().as_ref(), b"S" | b"s") => {
+                m.segment_duration = get_attr(&e, "d").ok_or(quick_xml::Error::TextNotFound)?;
+                m.latest_segment_number += 1;
+            }
             Ok(Event::Start(e)) => match e.name().as_ref() {
+                b"MPD" => {
+                    availability_start = get_attr::<String>(&e, "availabilityStartTime")
+                        .and_then(|t| parse_time(&t));
+                }
+                // YouTube gives the time the first segment was ingested,
+                // otherwise it is offset from the start of the stream
+                b"Period" => {
+                    let ingest_time =
+                        get_attr::<String>(&e, "yt:segmentIngestTime").and_then(|t| parse_time(&t));
+                    let offset = get_attr::<String>(&e, "start").and_then(|d| parse_duration(&d));
+                    m.start_time = ingest_time.or_else(|| Some(availability_start? + offset?));
+                }
                 b"SegmentList" => {
-                    m.latest_segment_number = get_attr::<i64>(&e, "startNumber")
-                        .map(|n: i64| n - 1)
-                        .ok_or(quick_xml::Error::TextNotFound)?;
+                    m.start_number =
+                        get_attr::<i64>(&e, "startNumber").ok_or(quick_xml::Error::TextNotFound)?;
+                    m.latest_segment_number = m.start_number - 1;
                 }
                 b"Representation" => {
                     m.representations
@@ -121,22 +169,63 @@ pub fn parse_manifest(manifest: &str) -> Result<Manifest, quick_xml::Error> {
 
 #[cfg(test)]
 mod tests {
-    #[test]
-    fn parse_manifest() {
-        // Read the test file
-        let fname = "dash_manifest.xml";
+    fn get_test_file(fname: &str) -> String {
         let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         d.push("resources/test/");
         d.push(fname);
-        let test_string =
-            std::fs::read_to_string(d).expect(format!("Could not read {}", fname).as_str());
+        std::fs::read_to_string(d).unwrap_or_else(|_| panic!("Could not read {}", fname))
+    }
+
+    #[test]
+    fn parse_manifest() {
+        // Read the test file
+        let test_string = get_test_file("dash_manifest.xml");
 
         // Parse the manifest
         let manifest = super::parse_manifest(&test_string).expect("Could not parse manifest");
 
         assert!(
-            manifest.representations.len() > 0,
+            !manifest.representations.is_empty(),
             "No representations found"
         );
+        assert_eq!(manifest.start_number, 602);
+        assert_eq!(manifest.latest_segment_number, 604);
+        assert_eq!(
+            manifest.segment_time(604).map(|t| t.to_rfc3339()),
+            Some("2023-06-21T14:54:20.061+00:00".to_string())
+        );
+        assert_eq!(
+            manifest.segment_time(600).map(|t| t.to_rfc3339()),
+            Some("2023-06-21T14:54:00.061+00:00".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_live_manifest() {
+        // Without an ingest time, the period start is used
+        let manifest = super::parse_manifest(&get_test_file("dash_manifest_live.xml"))
+            .expect("Could not parse manifest");
+
+        assert_eq!(manifest.representations.len(), 1);
+        assert_eq!(manifest.segment_duration, 2000.0);
+        assert_eq!(manifest.start_number, 720);
+        assert_eq!(manifest.latest_segment_number, 721);
+        assert_eq!(
+            manifest.segment_time(720).map(|t| t.to_rfc3339()),
+            Some("2023-06-21T15:04:16.500+00:00".to_string())
+        );
+        assert_eq!(
+            manifest.segment_time(722).map(|t| t.to_rfc3339()),
+            Some("2023-06-21T15:04:20.500+00:00".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_duration() {
+        let secs = |d| super::parse_duration(d).map(|d| d.num_milliseconds());
+        assert_eq!(secs("PT3010.000S"), Some(3_010_000));
+        assert_eq!(secs("PT1H2M3.5S"), Some(3_723_500));
+        assert_eq!(secs("P1D"), None);
+        assert_eq!(secs("PT5X"), None);
     }
 }
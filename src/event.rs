@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
+
+use crate::{info::Gap, source::Track, stats::StatisticsSnapshot};
+
+/// Channel the worker publishes events to. Subscribe with
+/// [`broadcast::Sender::subscribe`] before starting the worker to receive
+/// every event.
+pub type EventSender = broadcast::Sender<Event>;
+
+/// Why the worker stopped looking for new segments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    /// The stream is no longer live
+    NotLive,
+    /// No new segments appeared within the configured timeout
+    Timeout,
+    /// The worker was cancelled
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// A track was selected for download
+    FormatSelected(Track),
+    /// A segment was downloaded, with its size over all tracks
+    SegmentDownloaded {
+        seq: i64,
+        bytes: u64,
+        duration: Duration,
+    },
+    SegmentFailed {
+        seq: i64,
+        error: String,
+    },
+    /// The statistics after a segment was downloaded or failed
+    Progress(StatisticsSnapshot),
+    /// A range of segments is missing from the output, including any
+    /// adjacent segments that failed before
+    Gap(Gap),
+    /// The segment URLs were refreshed from a new player response
+    UrlRefreshed,
+    /// No more segments will be downloaded after the ones in progress
+    StreamEnded(EndReason),
+    /// All segments are downloaded and the playlists are closed
+    Finished {
+        segments_downloaded: u64,
+        bytes_downloaded: u64,
+    },
+    /// The download stopped because of an error. No more events follow.
+    Failed {
+        error: String,
+    },
+}
+
+/// Something that happened while downloading a stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub video_id: String,
+    pub time: DateTime<Utc>,
+    pub kind: EventKind,
+}
+
+/// Publishes an event if anyone is listening.
+pub(crate) fn emit(events: Option<&EventSender>, video_id: &str, kind: EventKind) {
+    if let Some(events) = events {
+        // Sending only fails if there are no subscribers
+        let _ = events.send(Event {
+            video_id: video_id.to_string(),
+            time: Utc::now(),
+            kind,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emit_to_subscribers() {
+        // Without a channel or subscribers, events are dropped
+        emit(None, "abc", EventKind::UrlRefreshed);
+        let (tx, rx) = broadcast::channel(4);
+        drop(rx);
+        emit(Some(&tx), "abc", EventKind::UrlRefreshed);
+
+        let mut rx = tx.subscribe();
+        emit(Some(&tx), "abc", EventKind::StreamEnded(EndReason::NotLive));
+        let event = rx.try_recv().expect("No event received");
+        assert_eq!(event.video_id, "abc");
+        assert_eq!(event.kind, EventKind::StreamEnded(EndReason::NotLive));
+        assert!(rx.try_recv().is_err());
+    }
+}
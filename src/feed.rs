@@ -0,0 +1,155 @@
+use chrono::{DateTime, Utc};
+use quick_xml::{events::Event, Reader};
+
+use crate::{
+    info::LiveStatus,
+    player_response::{InitialPlayerResponse, PlayerResponseError},
+    util,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum FeedError {
+    #[error("Could not fetch feed")]
+    DownloadError(#[from] util::DownloadError),
+    #[error("Could not parse feed")]
+    ParseError(#[from] quick_xml::Error),
+    #[error("Error getting initial player response")]
+    InitialPlayerResponseError(#[from] PlayerResponseError),
+}
+
+/// A video listed in a channel feed
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedEntry {
+    pub video_id: String,
+    pub channel_id: String,
+    pub title: String,
+    pub published: Option<DateTime<Utc>>,
+    pub updated: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feed {
+    pub title: String,
+    pub entries: Vec<FeedEntry>,
+}
+
+pub fn feed_url(channel_id: &str) -> String {
+    format!(
+        "https://www.youtube.com/feeds/videos.xml?channel_id={}",
+        channel_id
+    )
+}
+
+fn parse_date(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(s)
+        .ok()
+        .map(|d| d.with_timezone(&Utc))
+}
+
+/// Parses the Atom feed of a channel, which lists its most recent uploads,
+/// streams and scheduled streams.
+pub fn parse_feed(xml: &str) -> Result<Feed, quick_xml::Error> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut feed = Feed {
+        title: String::new(),
+        entries: Vec::new(),
+    };
+    let mut entry: Option<FeedEntry> = None;
+    let mut tag = Vec::new();
+
+    loop {
+        match reader.read_event() {
+            Err(e) => return Err(e),
+            Ok(Event::Eof) => break,
+            Ok(Event::Start(e)) => {
+                if e.name().as_ref() == b"entry" {
+                    entry = Some(FeedEntry {
+                        video_id: String::new(),
+                        channel_id: String::new(),
+                        title: String::new(),
+                        published: None,
+                        updated: None,
+                    });
+                }
+                tag = e.name().as_ref().to_vec();
+            }
+            Ok(Event::Text(e)) => {
+                let text = e.unescape()?;
+                match (entry.as_mut(), tag.as_slice()) {
+                    (Some(entry), b"yt:videoId") => entry.video_id = text.into_owned(),
+                    (Some(entry), b"yt:channelId") => entry.channel_id = text.into_owned(),
+                    (Some(entry), b"title") => entry.title = text.into_owned(),
+                    (Some(entry), b"published") => entry.published = parse_date(&text),
+                    (Some(entry), b"updated") => entry.updated = parse_date(&text),
+                    (None, b"title") => feed.title = text.into_owned(),
+                    _ => (),
+                }
+            }
+            Ok(Event::End(e)) => {
+                if e.name().as_ref() == b"entry" {
+                    if let Some(entry) = entry.take().filter(|e| !e.video_id.is_empty()) {
+                        feed.entries.push(entry);
+                    }
+                }
+                tag.clear();
+            }
+            _ => (),
+        }
+    }
+
+    Ok(feed)
+}
+
+pub async fn fetch_feed(client: &util::HttpClient, channel_id: &str) -> Result<Feed, FeedError> {
+    let xml = client.fetch_text(&feed_url(channel_id)).await?;
+    Ok(parse_feed(&xml)?)
+}
+
+/// Fetches the player response of a feed entry to find out whether it is
+/// live, upcoming or already over.
+pub async fn classify(
+    client: &util::HttpClient,
+    video_id: &str,
+) -> Result<(LiveStatus, InitialPlayerResponse), FeedError> {
+    let ipr = InitialPlayerResponse::fetch(client, video_id).await?;
+    Ok((LiveStatus::from_ipr(&ipr), ipr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/channel_feed.xml");
+        let xml = std::fs::read_to_string(d).expect("Could not read feed");
+        let feed = parse_feed(&xml).expect("Could not parse feed");
+
+        assert_eq!(feed.title, "Aqua Ch. 湊あくあ");
+        assert_eq!(
+            feed.entries
+                .iter()
+                .map(|e| e.video_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Fl1vM3scybw", "6u8D5tAzjns", "jfKfPfyJRdk"]
+        );
+
+        let entry = &feed.entries[0];
+        assert_eq!(entry.channel_id, "UC1opHUrw8rvnsadT-iGp7Cg");
+        assert_eq!(
+            entry.title,
+            "【歌枠】Singing & chatting【湊あくあ/ホロライブ】"
+        );
+        assert_eq!(
+            entry.published,
+            Some(
+                DateTime::parse_from_rfc3339("2024-02-14T12:00:11Z")
+                    .unwrap()
+                    .with_timezone(&Utc)
+            )
+        );
+    }
+}
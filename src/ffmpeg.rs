@@ -1,22 +1,75 @@
 use std::path::{Path, PathBuf};
 
+use crate::info;
+
 #[derive(thiserror::Error, Debug)]
 pub enum FfmpegError {
     #[error("I/O error")]
     IoError(#[from] std::io::Error),
+    #[error("ffmpeg exited with {0}")]
+    ExitStatus(std::process::ExitStatus),
 }
 
 pub struct Metadata {
     pub title: Option<String>,
+    /// Title at the start of the stream, if it was changed since
+    pub original_title: Option<String>,
     pub description: Option<String>,
     pub thumbnail: Option<PathBuf>,
     pub date: Option<String>,
     pub video_id: Option<String>,
+    /// Subtitle track to embed. Only used when muxing to MKV.
+    pub subtitles: Option<PathBuf>,
     pub faststart: bool,
 }
 
+impl Metadata {
+    /// Builds the metadata from the info sidecar of a download.
+    pub fn from_info(info: &info::InfoJson, thumbnail: Option<PathBuf>) -> Self {
+        Self {
+            title: Some(info.title.clone()),
+            original_title: Some(info.original_title().to_string()).filter(|t| *t != info.title),
+            description: Some(info.description.clone()),
+            thumbnail,
+            date: info
+                .player_response
+                .microformat
+                .as_ref()
+                .map(|m| m.player_microformat_renderer.publish_date.clone()),
+            video_id: Some(info.id.clone()),
+            subtitles: None,
+            faststart: true,
+        }
+    }
+}
+
+fn is_mkv(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.eq_ignore_ascii_case("mkv"))
+        .unwrap_or(false)
+}
+
 pub async fn mux(input: &Path, metadata: &Metadata, output: &Path) -> Result<(), FfmpegError> {
-    info!("Muxing {} to {}", input.display(), output.display());
+    mux_inputs(&[input], metadata, output).await
+}
+
+/// Muxes several inputs, such as separate audio and video files, into one
+/// output. The video input should come first so the thumbnail is the second
+/// video stream.
+pub async fn mux_inputs(
+    media: &[&Path],
+    metadata: &Metadata,
+    output: &Path,
+) -> Result<(), FfmpegError> {
+    info!(
+        "Muxing {} to {}",
+        media
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        output.display()
+    );
 
     let mut child = tokio::process::Command::new("ffmpeg");
 
@@ -26,22 +79,30 @@ pub async fn mux(input: &Path, metadata: &Metadata, output: &Path) -> Result<(),
         .arg("error")
         .arg("-y");
 
-    // Set input
-    child.arg("-i").arg(input);
+    // Set inputs
+    let subtitles = metadata.subtitles.as_ref().filter(|_| is_mkv(output));
+    let mut inputs = media.to_vec();
+    inputs.extend(metadata.thumbnail.as_deref());
+    inputs.extend(subtitles.map(|s| s.as_path()));
 
-    // Add metadata
-    if let Some(thumbnail) = &metadata.thumbnail {
-        child
-            .arg("-i")
-            .arg(thumbnail)
-            .arg("-map")
-            .arg("0")
-            .arg("-map")
-            .arg("1");
+    for input in &inputs {
+        child.arg("-i").arg(input);
+    }
+    if inputs.len() > 1 {
+        for i in 0..inputs.len() {
+            child.arg("-map").arg(i.to_string());
+        }
     }
+
+    // Add metadata
     if let Some(title) = &metadata.title {
         child.arg("-metadata").arg(format!("title={}", title));
     }
+    if let Some(original_title) = &metadata.original_title {
+        child
+            .arg("-metadata")
+            .arg(format!("original_title={}", original_title));
+    }
     if let Some(description) = &metadata.description {
         child
             .arg("-metadata")
@@ -62,15 +123,49 @@ pub async fn mux(input: &Path, metadata: &Metadata, output: &Path) -> Result<(),
         child.arg("-disposition:v:1").arg("attached_pic");
     }
 
-    if metadata.faststart {
-        child.arg("-movflags").arg("+faststart");
+    // MP4 drops tags it does not know about unless asked to keep them. MKV
+    // has no equivalent of these flags.
+    let mut movflags = String::new();
+    if !is_mkv(output) {
+        if metadata.faststart {
+            movflags.push_str("+faststart");
+        }
+        if metadata.original_title.is_some() {
+            movflags.push_str("+use_metadata_tags");
+        }
+    }
+    if !movflags.is_empty() {
+        child.arg("-movflags").arg(movflags);
     }
 
     // Set output
     child.arg(output);
 
-    child.spawn().map_err(FfmpegError::IoError)?.wait().await?;
+    let status = child.status().await?;
+    if !status.success() {
+        return Err(FfmpegError::ExitStatus(status));
+    }
     info!("Muxing complete");
 
     Ok(())
 }
+
+/// Converts an image to the format implied by the output's extension.
+pub async fn convert_image(input: &Path, output: &Path) -> Result<(), FfmpegError> {
+    let status = tokio::process::Command::new("ffmpeg")
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-y")
+        .arg("-i")
+        .arg(input)
+        .arg(output)
+        .status()
+        .await?;
+
+    if !status.success() {
+        return Err(FfmpegError::ExitStatus(status));
+    }
+
+    Ok(())
+}
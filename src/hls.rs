@@ -1,59 +1,168 @@
-use std::{path::Path, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::future::try_join_all;
 use tokio::{
-    fs::File,
+    fs::{File, OpenOptions},
     io::{self, AsyncWriteExt},
-    try_join,
 };
 
-use crate::dash::{Manifest, Representation};
+use crate::source::{Track, TrackKind};
+
+const EVENT_PLAYLIST: &str = "#EXT-X-PLAYLIST-TYPE:EVENT\n";
+const VOD_PLAYLIST: &str = "#EXT-X-PLAYLIST-TYPE:VOD\n";
+
+/// Formats a segment duration the way it is written to `EXTINF`.
+fn format_duration(duration: Duration) -> String {
+    format!("{:.1}", duration.as_secs_f64())
+}
+
+/// The duration of a segment rounded to whole seconds, as it is compared to
+/// the target duration. Rounds the written value, not the exact one.
+fn rounded_duration(duration: Duration) -> u64 {
+    format_duration(duration)
+        .parse::<f64>()
+        .map_or(0, |d| d.round() as u64)
+}
 
+/// A media playlist that segments are appended to while downloading. It is
+/// an `EVENT` playlist until finished, when it becomes a `VOD` playlist.
 pub struct LivePlaylist {
+    path: PathBuf,
     file: File,
+    target_duration: u64,
 }
 
 impl LivePlaylist {
-    pub async fn new(fname: &str, segment_duration: Duration) -> io::Result<Self> {
+    /// Creates a playlist for the segments of `track`. The target duration
+    /// never changes afterwards, so it is set to half again as long as
+    /// `segment_duration`, rounded up, to leave room for longer segments.
+    ///
+    /// Fragmented MP4 tracks are written as version 7, which players expect
+    /// for fMP4 media described with `EXT-X-MAP`. Every YouTube segment
+    /// carries its own initialization section, so no map is written and the
+    /// segments are marked with `EXT-X-INDEPENDENT-SEGMENTS` instead.
+    pub async fn new(fname: &str, segment_duration: Duration, track: &Track) -> io::Result<Self> {
         let mut file = File::create(fname).await?;
+        let target_duration = ((segment_duration.as_secs_f64() * 1.5).ceil() as u64).max(1);
+        let fragmented = track.extension() == "mp4";
 
         // Write the header
-        file.write_all(
-            format!(
-                "#EXTM3U
+        let mut header = format!(
+            "#EXTM3U
+#EXT-X-VERSION:{}
 #EXT-X-TARGETDURATION:{}
-#EXT-X-MEDIA-SEQUENCE:{}
-#EXT-X-VERSION:3\n",
-                segment_duration.as_secs_f32(),
-                0,
-            )
-            .as_bytes(),
-        )
-        .await?;
+#EXT-X-MEDIA-SEQUENCE:0
+{}",
+            if fragmented { 7 } else { 3 },
+            target_duration,
+            EVENT_PLAYLIST,
+        );
+        if fragmented {
+            header.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+        }
+        file.write_all(header.as_bytes()).await?;
 
-        Ok(Self { file })
+        Ok(Self {
+            path: PathBuf::from(fname),
+            file,
+            target_duration,
+        })
     }
 
-    pub async fn add_segment(&mut self, fname: &str, segment_duration: Duration) -> io::Result<()> {
-        self.file
-            .write_all(
-                format!(
-                    "#EXTINF:{:.1},\n{}\n",
-                    segment_duration.as_secs_f32(),
-                    fname
-                )
-                .as_bytes(),
-            )
-            .await
+    /// Opens the playlist for `track` to append to it, such as after a
+    /// restart, or creates it if it does not exist. A finished playlist is
+    /// turned back into an `EVENT` playlist, and its target duration is kept
+    /// as it may not change.
+    pub async fn resume(
+        fname: &str,
+        segment_duration: Duration,
+        track: &Track,
+    ) -> io::Result<Self> {
+        let text = match tokio::fs::read_to_string(fname).await {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Self::new(fname, segment_duration, track).await
+            }
+            Err(e) => return Err(e),
+        };
+        let parsed = parse_media_playlist(&text, "file:///")
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let file = OpenOptions::new().append(true).open(fname).await?;
+        let mut playlist = Self {
+            path: PathBuf::from(fname),
+            file,
+            target_duration: parsed.target_duration.as_secs_f64().ceil() as u64,
+        };
+        playlist
+            .rewrite(|text| {
+                text.replace("#EXT-X-ENDLIST\n", "")
+                    .replacen(VOD_PLAYLIST, EVENT_PLAYLIST, 1)
+            })
+            .await?;
+
+        Ok(playlist)
     }
 
+    /// Appends a segment, with the wall-clock time it started at if known.
+    pub async fn add_segment(
+        &mut self,
+        fname: &str,
+        segment_duration: Duration,
+        time: Option<DateTime<Utc>>,
+    ) -> io::Result<()> {
+        // The real duration is kept so the timeline stays correct, even
+        // though strict players may reject the playlist
+        if rounded_duration(segment_duration) > self.target_duration {
+            error!(
+                "{} is {:.1}s long, longer than the target duration of {}s",
+                fname,
+                segment_duration.as_secs_f64(),
+                self.target_duration
+            );
+        }
+
+        let mut entry = String::new();
+        if let Some(time) = time {
+            entry.push_str(&format!(
+                "#EXT-X-PROGRAM-DATE-TIME:{}\n",
+                time.to_rfc3339_opts(SecondsFormat::Millis, true)
+            ));
+        }
+        entry.push_str(&format!(
+            "#EXTINF:{},\n{}\n",
+            format_duration(segment_duration),
+            fname
+        ));
+        self.file.write_all(entry.as_bytes()).await
+    }
+
+    /// Ends the playlist and marks it as `VOD`.
     pub async fn finish(&mut self) -> io::Result<()> {
-        self.file.write_all(b"#EXT-X-ENDLIST\n").await
+        self.file.write_all(b"#EXT-X-ENDLIST\n").await?;
+        self.rewrite(|text| text.replacen(EVENT_PLAYLIST, VOD_PLAYLIST, 1))
+            .await
+    }
+
+    /// Changes what was written so far. The playlist is written to a
+    /// temporary file first, so readers never see a partial playlist.
+    async fn rewrite(&mut self, f: impl FnOnce(&str) -> String) -> io::Result<()> {
+        self.file.flush().await?;
+        let text = tokio::fs::read_to_string(&self.path).await?;
+        let tmp = self.path.with_extension("m3u8.tmp");
+        tokio::fs::write(&tmp, f(&text)).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        self.file = OpenOptions::new().append(true).open(&self.path).await?;
+        Ok(())
     }
 }
 
 pub struct IndexPlaylist {
-    pub playlist_audio: LivePlaylist,
-    pub playlist_video: LivePlaylist,
+    pub playlists: Vec<LivePlaylist>,
 }
 
 fn replace_extension(fname: &str, ext: &str) -> String {
@@ -63,74 +172,799 @@ fn replace_extension(fname: &str, ext: &str) -> String {
 }
 
 impl IndexPlaylist {
+    /// Creates a master playlist at `fname` with one media playlist per
+    /// track. Audio tracks are added as alternative renditions of the video
+    /// or muxed tracks.
     pub async fn new(
         fname: &str,
-        manifest: &Manifest,
-        audio: &Representation,
-        video: &Representation,
+        segment_duration: Duration,
+        tracks: &[Track],
+    ) -> io::Result<Self> {
+        Self::open(fname, segment_duration, tracks, false).await
+    }
+
+    /// Like [`Self::new`], but appends to the media playlists that already
+    /// exist, keeping the segments in them.
+    pub async fn resume(
+        fname: &str,
+        segment_duration: Duration,
+        tracks: &[Track],
+    ) -> io::Result<Self> {
+        Self::open(fname, segment_duration, tracks, true).await
+    }
+
+    async fn open(
+        fname: &str,
+        segment_duration: Duration,
+        tracks: &[Track],
+        resume: bool,
     ) -> io::Result<Self> {
         let mut file = File::create(fname).await?;
 
-        let path_playlist_audio = replace_extension(fname, &format!("f{}.m3u8", audio.id));
-        let path_playlist_video = replace_extension(fname, &format!("f{}.m3u8", video.id));
-        let (fname_playlist_audio, fname_playlist_video) = (
-            Path::new(&path_playlist_audio)
-                .file_name()
-                .expect("should never happen")
-                .to_string_lossy(),
-            Path::new(&path_playlist_video)
-                .file_name()
-                .expect("should never happen")
-                .to_string_lossy(),
-        );
+        let paths = tracks
+            .iter()
+            .map(|t| replace_extension(fname, &format!("f{}.m3u8", t.id)))
+            .collect::<Vec<_>>();
+        let fnames = paths
+            .iter()
+            .map(|p| {
+                Path::new(p)
+                    .file_name()
+                    .expect("should never happen")
+                    .to_string_lossy()
+            })
+            .collect::<Vec<_>>();
 
         // Write the header
-        file.write_all(
-            format!(
-                "#EXTM3U
-#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"f{}\",DEFAULT=YES,AUTOSELECT=YES,URI=\"{}\"
-#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\",AUDIO=\"f{}\"
-{}\n",
-                audio.id,
-                fname_playlist_audio,
-                video.bandwidth,
-                video.codecs,
-                audio.id,
-                fname_playlist_video,
-            )
-            .as_bytes(),
-        )
-        .await?;
+        let mut header = String::from("#EXTM3U\n");
+        let audio = tracks.iter().position(|t| t.kind == TrackKind::Audio);
+        if let Some(i) = audio {
+            header.push_str(&format!(
+                "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"f{}\",DEFAULT=YES,AUTOSELECT=YES,URI=\"{}\"\n",
+                tracks[i].id, fnames[i],
+            ));
+        }
+        for (track, fname) in tracks.iter().zip(&fnames) {
+            if track.kind == TrackKind::Audio {
+                continue;
+            }
+            header.push_str(&format!(
+                "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\"",
+                track.bandwidth, track.codecs,
+            ));
+            if let Some(i) = audio {
+                header.push_str(&format!(",AUDIO=\"f{}\"", tracks[i].id));
+            }
+            header.push_str(&format!("\n{}\n", fname));
+        }
+        file.write_all(header.as_bytes()).await?;
 
-        let dur = Duration::from_millis(manifest.segment_duration as u64);
-        let (playlist_audio, playlist_video) = try_join!(
-            LivePlaylist::new(&path_playlist_audio, dur),
-            LivePlaylist::new(&path_playlist_video, dur),
-        )?;
+        let playlists = try_join_all(paths.iter().zip(tracks).map(|(p, t)| async move {
+            if resume {
+                LivePlaylist::resume(p, segment_duration, t).await
+            } else {
+                LivePlaylist::new(p, segment_duration, t).await
+            }
+        }))
+        .await?;
 
-        Ok(Self {
-            playlist_audio,
-            playlist_video,
-        })
+        Ok(Self { playlists })
     }
 
+    /// Adds a segment to every media playlist, with one file name per track
+    /// in the order the tracks were given.
     pub async fn add_segment(
         &mut self,
-        fname_audio: &str,
-        fname_video: &str,
+        fnames: &[String],
         segment_duration: Duration,
+        time: Option<DateTime<Utc>>,
     ) -> io::Result<()> {
-        try_join!(
-            self.playlist_audio
-                .add_segment(fname_audio, segment_duration),
-            self.playlist_video
-                .add_segment(fname_video, segment_duration),
-        )?;
+        try_join_all(
+            self.playlists
+                .iter_mut()
+                .zip(fnames)
+                .map(|(p, fname)| p.add_segment(fname, segment_duration, time)),
+        )
+        .await?;
         Ok(())
     }
 
     pub async fn finish(&mut self) -> io::Result<()> {
-        try_join!(self.playlist_audio.finish(), self.playlist_video.finish())?;
+        try_join_all(self.playlists.iter_mut().map(|p| p.finish())).await?;
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ParseError {
+    #[error("Not an M3U playlist")]
+    NotAPlaylist,
+    #[error("Invalid value for {0}")]
+    InvalidValue(&'static str),
+    #[error("Invalid URI: {0}")]
+    InvalidUri(#[from] url::ParseError),
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ValidationError {
+    #[error("Not an M3U playlist")]
+    NotAPlaylist,
+    #[error("Invalid value for {0}")]
+    InvalidValue(&'static str),
+    #[error("{0} appears more than once")]
+    DuplicateTag(&'static str),
+    #[error("Missing EXT-X-TARGETDURATION")]
+    MissingTargetDuration,
+    #[error("Segment {0} has no EXTINF")]
+    MissingDuration(String),
+    #[error("Segment {uri} is {duration}s long, more than the target duration of {target}s")]
+    SegmentTooLong {
+        uri: String,
+        duration: u64,
+        target: u64,
+    },
+    #[error("{feature} needs version {required}, but the playlist is version {version}")]
+    VersionTooLow {
+        feature: &'static str,
+        required: u64,
+        version: u64,
+    },
+    #[error("Fragmented MP4 segment {0} has no EXT-X-MAP and is not marked as independent")]
+    MissingInitialization(String),
+    #[error("VOD playlist has no EXT-X-ENDLIST")]
+    UnfinishedVod,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub uri: String,
+    pub bandwidth: i64,
+    pub codecs: Option<String>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub frame_rate: Option<f64>,
+    pub audio_group: Option<String>,
+}
+
+impl Variant {
+    /// The itag of the variant, taken from its URI. YouTube puts it in the
+    /// path as `/itag/<n>/`.
+    pub fn itag(&self) -> Option<i64> {
+        let mut parts = self.uri.split('/');
+        parts.find(|p| *p == "itag")?;
+        parts.next()?.parse().ok()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rendition {
+    pub media_type: String,
+    pub group_id: String,
+    pub name: Option<String>,
+    pub uri: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MasterPlaylist {
+    pub variants: Vec<Variant>,
+    pub renditions: Vec<Rendition>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaSegment {
+    pub sequence: i64,
+    pub duration: Duration,
+    pub uri: String,
+    pub program_date_time: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaPlaylist {
+    pub target_duration: Duration,
+    pub media_sequence: i64,
+    pub segments: Vec<MediaSegment>,
+    pub end_list: bool,
+}
+
+/// Splits an attribute list into key-value pairs, removing quotes from
+/// quoted values.
+fn parse_attributes(attrs: &str) -> Vec<(&str, &str)> {
+    let mut out = Vec::new();
+    let mut rest = attrs.trim();
+
+    while let Some(idx_eq) = rest.find('=') {
+        let key = rest[..idx_eq].trim();
+        rest = &rest[idx_eq + 1..];
+
+        let value;
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let idx_end = quoted.find('"').unwrap_or(quoted.len());
+            value = &quoted[..idx_end];
+            rest = quoted.get(idx_end + 1..).unwrap_or_default();
+        } else {
+            let idx_end = rest.find(',').unwrap_or(rest.len());
+            value = &rest[..idx_end];
+            rest = &rest[idx_end..];
+        }
+        out.push((key, value));
+
+        rest = rest.trim_start_matches(',');
+    }
+
+    out
+}
+
+fn resolve_uri(base: &url::Url, uri: &str) -> Result<String, ParseError> {
+    Ok(base.join(uri)?.to_string())
+}
+
+/// Parses a master playlist. Relative URIs are resolved against `base_url`.
+pub fn parse_master_playlist(text: &str, base_url: &str) -> Result<MasterPlaylist, ParseError> {
+    let base = url::Url::parse(base_url)?;
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        return Err(ParseError::NotAPlaylist);
+    }
+
+    let mut playlist = MasterPlaylist {
+        variants: Vec::new(),
+        renditions: Vec::new(),
+    };
+
+    while let Some(line) = lines.next() {
+        if let Some(attrs) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            let mut variant = Variant {
+                uri: String::new(),
+                bandwidth: 0,
+                codecs: None,
+                width: None,
+                height: None,
+                frame_rate: None,
+                audio_group: None,
+            };
+            for (key, value) in parse_attributes(attrs) {
+                match key {
+                    "BANDWIDTH" => {
+                        variant.bandwidth = value
+                            .parse()
+                            .map_err(|_| ParseError::InvalidValue("BANDWIDTH"))?
+                    }
+                    "CODECS" => variant.codecs = Some(value.to_string()),
+                    "RESOLUTION" => {
+                        let (w, h) = value
+                            .split_once('x')
+                            .ok_or(ParseError::InvalidValue("RESOLUTION"))?;
+                        variant.width = w.parse().ok();
+                        variant.height = h.parse().ok();
+                    }
+                    "FRAME-RATE" => variant.frame_rate = value.parse().ok(),
+                    "AUDIO" => variant.audio_group = Some(value.to_string()),
+                    _ => (),
+                }
+            }
+
+            // The URI is on the next line
+            let uri = lines.next().ok_or(ParseError::InvalidValue("URI"))?;
+            variant.uri = resolve_uri(&base, uri)?;
+            playlist.variants.push(variant);
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-MEDIA:") {
+            let mut rendition = Rendition {
+                media_type: String::new(),
+                group_id: String::new(),
+                name: None,
+                uri: None,
+            };
+            for (key, value) in parse_attributes(attrs) {
+                match key {
+                    "TYPE" => rendition.media_type = value.to_string(),
+                    "GROUP-ID" => rendition.group_id = value.to_string(),
+                    "NAME" => rendition.name = Some(value.to_string()),
+                    "URI" => rendition.uri = Some(resolve_uri(&base, value)?),
+                    _ => (),
+                }
+            }
+            playlist.renditions.push(rendition);
+        }
+    }
+
+    Ok(playlist)
+}
+
+/// Parses a media playlist. Relative URIs are resolved against `base_url`.
+pub fn parse_media_playlist(text: &str, base_url: &str) -> Result<MediaPlaylist, ParseError> {
+    let base = url::Url::parse(base_url)?;
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        return Err(ParseError::NotAPlaylist);
+    }
+
+    let mut playlist = MediaPlaylist {
+        target_duration: Duration::ZERO,
+        media_sequence: 0,
+        segments: Vec::new(),
+        end_list: false,
+    };
+    let mut duration = None;
+    let mut program_date_time = None;
+
+    for line in lines {
+        if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+            let secs: f64 = value
+                .parse()
+                .map_err(|_| ParseError::InvalidValue("EXT-X-TARGETDURATION"))?;
+            playlist.target_duration = Duration::from_secs_f64(secs);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            playlist.media_sequence = value
+                .parse()
+                .map_err(|_| ParseError::InvalidValue("EXT-X-MEDIA-SEQUENCE"))?;
+        } else if let Some(value) = line.strip_prefix("#EXTINF:") {
+            let secs: f64 = value
+                .split(',')
+                .next()
+                .and_then(|d| d.parse().ok())
+                .ok_or(ParseError::InvalidValue("EXTINF"))?;
+            duration = Some(Duration::from_secs_f64(secs));
+        } else if let Some(value) = line.strip_prefix("#EXT-X-PROGRAM-DATE-TIME:") {
+            let time = DateTime::parse_from_rfc3339(value)
+                .map_err(|_| ParseError::InvalidValue("EXT-X-PROGRAM-DATE-TIME"))?;
+            program_date_time = Some(time.with_timezone(&Utc));
+        } else if line == "#EXT-X-ENDLIST" {
+            playlist.end_list = true;
+        } else if !line.starts_with('#') {
+            // Segments without their own date follow on from the previous one
+            let time = program_date_time.take().or_else(|| {
+                let last = playlist.segments.last()?;
+                Some(last.program_date_time? + chrono::Duration::from_std(last.duration).ok()?)
+            });
+            playlist.segments.push(MediaSegment {
+                sequence: playlist.media_sequence + playlist.segments.len() as i64,
+                duration: duration.take().unwrap_or(playlist.target_duration),
+                uri: resolve_uri(&base, line)?,
+                program_date_time: time,
+            });
+        }
+    }
+
+    Ok(playlist)
+}
+
+/// Checks that a media playlist follows the rules of RFC 8216 that
+/// players are strict about: an integer target duration that no rounded
+/// segment duration exceeds, a version high enough for the features used, and
+/// initialization of fragmented MP4 segments.
+pub fn validate_media_playlist(text: &str) -> Result<(), ValidationError> {
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        return Err(ValidationError::NotAPlaylist);
+    }
+    let lines = lines.collect::<Vec<_>>();
+
+    // Tags that apply to the whole playlist, wherever they are
+    let mut version = None;
+    let mut target = None;
+    let mut playlist_type = None;
+    let mut independent = false;
+    let mut end_list = false;
+    for line in &lines {
+        if let Some(value) = line.strip_prefix("#EXT-X-VERSION:") {
+            let value = value
+                .parse::<u64>()
+                .map_err(|_| ValidationError::InvalidValue("EXT-X-VERSION"))?;
+            if version.replace(value).is_some() {
+                return Err(ValidationError::DuplicateTag("EXT-X-VERSION"));
+            }
+        } else if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+            // A decimal integer, so no sign or fraction
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ValidationError::InvalidValue("EXT-X-TARGETDURATION"));
+            }
+            let value = value
+                .parse::<u64>()
+                .map_err(|_| ValidationError::InvalidValue("EXT-X-TARGETDURATION"))?;
+            if target.replace(value).is_some() {
+                return Err(ValidationError::DuplicateTag("EXT-X-TARGETDURATION"));
+            }
+        } else if let Some(value) = line.strip_prefix("#EXT-X-PLAYLIST-TYPE:") {
+            if value != "EVENT" && value != "VOD" {
+                return Err(ValidationError::InvalidValue("EXT-X-PLAYLIST-TYPE"));
+            }
+            if playlist_type.replace(value).is_some() {
+                return Err(ValidationError::DuplicateTag("EXT-X-PLAYLIST-TYPE"));
+            }
+        } else if *line == "#EXT-X-INDEPENDENT-SEGMENTS" {
+            independent = true;
+        } else if *line == "#EXT-X-ENDLIST" {
+            end_list = true;
+        }
+    }
+    let version = version.unwrap_or(1);
+    let target = target.ok_or(ValidationError::MissingTargetDuration)?;
+    let require = |feature, required| {
+        if version < required {
+            return Err(ValidationError::VersionTooLow {
+                feature,
+                required,
+                version,
+            });
+        }
         Ok(())
+    };
+    if playlist_type == Some("VOD") && !end_list {
+        return Err(ValidationError::UnfinishedVod);
+    }
+
+    let mut duration = None;
+    let mut mapped = false;
+    for line in lines {
+        if let Some(value) = line.strip_prefix("#EXTINF:") {
+            let value = value.split(',').next().unwrap_or_default();
+            let secs = value
+                .parse::<f64>()
+                .ok()
+                .filter(|d| *d >= 0.0)
+                .ok_or(ValidationError::InvalidValue("EXTINF"))?;
+            if value.contains('.') {
+                require("Fractional EXTINF", 3)?;
+            }
+            duration = Some(secs.round() as u64);
+        } else if line.starts_with("#EXT-X-MAP:") {
+            require("EXT-X-MAP", 6)?;
+            mapped = true;
+        } else if !line.starts_with('#') {
+            let duration = duration
+                .take()
+                .ok_or_else(|| ValidationError::MissingDuration(line.to_string()))?;
+            if duration > target {
+                return Err(ValidationError::SegmentTooLong {
+                    uri: line.to_string(),
+                    duration,
+                    target,
+                });
+            }
+
+            let path = line.split(['?', '#']).next().unwrap_or_default();
+            if path.ends_with(".mp4") || path.ends_with(".m4s") {
+                require("Fragmented MP4", 6)?;
+                if !mapped && !independent {
+                    return Err(ValidationError::MissingInitialization(line.to_string()));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_file(fname: &str) -> String {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/");
+        d.push(fname);
+        std::fs::read_to_string(d).unwrap_or_else(|_| panic!("Could not read {}", fname))
+    }
+
+    #[test]
+    fn attributes() {
+        let attrs = parse_attributes(
+            r#"BANDWIDTH=1280000,CODECS="avc1.4d401f,mp4a.40.2",RESOLUTION=1280x720"#,
+        );
+        assert_eq!(
+            attrs,
+            vec![
+                ("BANDWIDTH", "1280000"),
+                ("CODECS", "avc1.4d401f,mp4a.40.2"),
+                ("RESOLUTION", "1280x720"),
+            ]
+        );
+    }
+
+    #[test]
+    fn master_playlist() {
+        let text = get_test_file("hls_master.m3u8");
+        let playlist = parse_master_playlist(
+            &text,
+            "https://manifest.googlevideo.com/api/manifest/hls_variant/id/abc/file/index.m3u8",
+        )
+        .expect("Could not parse playlist");
+
+        assert_eq!(playlist.variants.len(), 3);
+        let best = playlist
+            .variants
+            .iter()
+            .max_by_key(|v| v.bandwidth)
+            .unwrap();
+        assert_eq!(best.height, Some(1080));
+        assert_eq!(best.frame_rate, Some(30.0));
+        assert_eq!(best.itag(), Some(96));
+        assert_eq!(best.codecs.as_deref(), Some("avc1.640028,mp4a.40.2"));
+    }
+
+    #[test]
+    fn media_playlist() {
+        let text = get_test_file("hls_media.m3u8");
+        let playlist = parse_media_playlist(
+            &text,
+            "https://manifest.googlevideo.com/api/manifest/hls_playlist/index.m3u8",
+        )
+        .expect("Could not parse playlist");
+
+        assert_eq!(playlist.target_duration, Duration::from_secs(5));
+        assert_eq!(playlist.media_sequence, 1400);
+        assert_eq!(playlist.segments.len(), 3);
+        assert_eq!(playlist.segments[2].sequence, 1402);
+        assert_eq!(playlist.segments[2].duration, Duration::from_millis(4500));
+        assert!(playlist.segments[0].uri.contains("/sq/1400/"));
+        assert!(!playlist.end_list);
+        assert_eq!(
+            playlist.segments[2]
+                .program_date_time
+                .map(|t| t.to_rfc3339()),
+            Some("2023-06-21T16:01:00.061+00:00".to_string())
+        );
+
+        // Only the first segment is dated
+        let mut lines = text.lines().collect::<Vec<_>>();
+        lines.retain(|l| !l.contains("16:00:55") && !l.contains("16:01:00"));
+        let playlist = parse_media_playlist(
+            &lines.join("\n"),
+            "https://manifest.googlevideo.com/api/manifest/hls_playlist/index.m3u8",
+        )
+        .expect("Could not parse playlist");
+        assert_eq!(
+            playlist.segments[2]
+                .program_date_time
+                .map(|t| t.to_rfc3339()),
+            Some("2023-06-21T16:01:00.061+00:00".to_string())
+        );
+
+        assert!(parse_media_playlist("<html>", "https://example.com").is_err());
+    }
+
+    fn track(kind: TrackKind) -> Track {
+        Track {
+            id: 140,
+            kind,
+            codecs: "mp4a.40.2".to_string(),
+            bandwidth: 128000,
+            width: None,
+            height: None,
+            frame_rate: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_playlist() {
+        let dir = std::env::temp_dir().join(format!("yta-hls-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fname = dir.join("index.m3u8");
+        let fname = fname.to_str().unwrap();
+        let tracks = [track(TrackKind::Muxed)];
+        let media = dir.join("index.f140.m3u8");
+
+        // A download that was stopped
+        let mut playlist = IndexPlaylist::new(fname, Duration::from_secs(5), &tracks)
+            .await
+            .unwrap();
+        for seq in 0..2 {
+            playlist
+                .add_segment(&[format!("seq_{}.ts", seq)], Duration::from_secs(5), None)
+                .await
+                .unwrap();
+        }
+        playlist.finish().await.unwrap();
+
+        // Resuming keeps the segments and the target duration, even if the
+        // segment duration changed
+        let mut playlist = IndexPlaylist::resume(fname, Duration::from_secs(2), &tracks)
+            .await
+            .unwrap();
+        let text = std::fs::read_to_string(&media).unwrap();
+        assert!(text.contains(EVENT_PLAYLIST));
+        assert!(!text.contains("#EXT-X-ENDLIST"));
+        playlist
+            .add_segment(&["seq_5.ts".to_string()], Duration::from_secs(5), None)
+            .await
+            .unwrap();
+        playlist.finish().await.unwrap();
+
+        let text = std::fs::read_to_string(&media).unwrap();
+        assert_eq!(text.matches("#EXTM3U").count(), 1);
+        assert_eq!(text.matches("#EXT-X-ENDLIST").count(), 1);
+        assert!(text.contains("#EXT-X-TARGETDURATION:8\n"));
+        assert_eq!(validate_media_playlist(&text), Ok(()));
+        let parsed = parse_media_playlist(&text, "file:///").unwrap();
+        let uris = parsed
+            .segments
+            .iter()
+            .map(|s| s.uri.rsplit('/').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(uris, ["seq_0.ts", "seq_1.ts", "seq_5.ts"]);
+
+        // Without a previous download, a new playlist is created
+        std::fs::remove_file(&media).unwrap();
+        IndexPlaylist::resume(fname, Duration::from_secs(5), &tracks)
+            .await
+            .unwrap();
+        let text = std::fs::read_to_string(&media).unwrap();
+        assert!(text.ends_with(EVENT_PLAYLIST));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn live_playlist() {
+        let dir = std::env::temp_dir().join(format!("yta-hls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fname = dir.join("index.m3u8");
+        let fname = fname.to_str().unwrap();
+
+        let time = DateTime::parse_from_rfc3339("2023-06-21T16:00:50.061+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut playlist =
+            LivePlaylist::new(fname, Duration::from_millis(4500), &track(TrackKind::Muxed))
+                .await
+                .unwrap();
+        playlist
+            .add_segment("0.ts", Duration::from_secs(5), Some(time))
+            .await
+            .unwrap();
+        playlist
+            .add_segment("1.ts", Duration::from_millis(4960), None)
+            .await
+            .unwrap();
+        playlist.file.flush().await.unwrap();
+
+        let text = std::fs::read_to_string(fname).unwrap();
+        assert!(text.contains("#EXT-X-VERSION:3\n"));
+        assert!(text.contains("#EXT-X-TARGETDURATION:7\n"));
+        assert!(text.contains(EVENT_PLAYLIST));
+        assert!(text
+            .contains("#EXT-X-PROGRAM-DATE-TIME:2023-06-21T16:00:50.061Z\n#EXTINF:5.0,\n0.ts\n"));
+        assert!(text.ends_with("#EXTINF:5.0,\n1.ts\n"));
+        assert_eq!(validate_media_playlist(&text), Ok(()));
+
+        // A longer segment fits within the headroom of the target duration
+        playlist
+            .add_segment("2.ts", Duration::from_millis(6500), None)
+            .await
+            .unwrap();
+        playlist.file.flush().await.unwrap();
+        let text = std::fs::read_to_string(fname).unwrap();
+        assert_eq!(text.matches("#EXT-X-TARGETDURATION:").count(), 1);
+        assert!(text.ends_with("#EXTINF:6.5,\n2.ts\n"));
+        assert_eq!(validate_media_playlist(&text), Ok(()));
+
+        playlist.finish().await.unwrap();
+        let text = std::fs::read_to_string(fname).unwrap();
+        assert!(!text.contains(EVENT_PLAYLIST));
+        assert!(text.contains(VOD_PLAYLIST));
+        assert!(text.ends_with("#EXT-X-ENDLIST\n"));
+        assert_eq!(validate_media_playlist(&text), Ok(()));
+
+        let parsed = parse_media_playlist(&text, "file:///").unwrap();
+        assert!(parsed.end_list);
+        assert_eq!(parsed.segments.len(), 3);
+        assert_eq!(parsed.segments[0].program_date_time, Some(time));
+        assert_eq!(parsed.segments[2].duration, Duration::from_millis(6500));
+
+        // Fragmented MP4 tracks, with a segment duration below a second
+        let mut playlist =
+            LivePlaylist::new(fname, Duration::from_millis(400), &track(TrackKind::Audio))
+                .await
+                .unwrap();
+        playlist
+            .add_segment("0.mp4", Duration::from_millis(400), None)
+            .await
+            .unwrap();
+        playlist.finish().await.unwrap();
+        let text = std::fs::read_to_string(fname).unwrap();
+        assert!(text.contains("#EXT-X-VERSION:7\n"));
+        assert!(text.contains("#EXT-X-TARGETDURATION:1\n"));
+        assert!(text.contains("#EXT-X-INDEPENDENT-SEGMENTS\n"));
+        assert_eq!(validate_media_playlist(&text), Ok(()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn validate_playlist() {
+        assert_eq!(
+            validate_media_playlist(&get_test_file("hls_media.m3u8")),
+            Ok(())
+        );
+
+        let playlist = |header: &str, segments: &str| {
+            validate_media_playlist(&format!("#EXTM3U\n{}\n{}", header, segments))
+        };
+        let header = "#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:5";
+
+        // Durations are compared after rounding
+        assert_eq!(playlist(header, "#EXTINF:5.4,\n0.ts"), Ok(()));
+        assert_eq!(
+            playlist(header, "#EXTINF:5.5,\n0.ts"),
+            Err(ValidationError::SegmentTooLong {
+                uri: "0.ts".to_string(),
+                duration: 6,
+                target: 5,
+            })
+        );
+
+        assert_eq!(
+            playlist("#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:5.0", ""),
+            Err(ValidationError::InvalidValue("EXT-X-TARGETDURATION"))
+        );
+        assert_eq!(
+            playlist("#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:-5", ""),
+            Err(ValidationError::InvalidValue("EXT-X-TARGETDURATION"))
+        );
+        assert_eq!(
+            playlist("#EXT-X-VERSION:3", "#EXTINF:5.0,\n0.ts"),
+            Err(ValidationError::MissingTargetDuration)
+        );
+        assert_eq!(
+            playlist(&format!("{}\n#EXT-X-TARGETDURATION:6", header), ""),
+            Err(ValidationError::DuplicateTag("EXT-X-TARGETDURATION"))
+        );
+        assert_eq!(
+            playlist(header, "#EXTINF:5.0,\n0.ts\n1.ts"),
+            Err(ValidationError::MissingDuration("1.ts".to_string()))
+        );
+        assert_eq!(
+            playlist("#EXT-X-TARGETDURATION:5", "#EXTINF:4.5,\n0.ts"),
+            Err(ValidationError::VersionTooLow {
+                feature: "Fractional EXTINF",
+                required: 3,
+                version: 1,
+            })
+        );
+        assert_eq!(
+            playlist("#EXT-X-TARGETDURATION:5", "#EXTINF:5,\n0.ts"),
+            Ok(())
+        );
+
+        // Fragmented MP4 needs initialization
+        assert_eq!(
+            playlist(header, "#EXTINF:5.0,\n0.mp4"),
+            Err(ValidationError::VersionTooLow {
+                feature: "Fragmented MP4",
+                required: 6,
+                version: 3,
+            })
+        );
+        let header = "#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:5";
+        assert_eq!(
+            playlist(header, "#EXTINF:5.0,\n0.mp4?sq=0"),
+            Err(ValidationError::MissingInitialization(
+                "0.mp4?sq=0".to_string()
+            ))
+        );
+        assert_eq!(
+            playlist(header, "#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:5.0,\n0.mp4"),
+            Ok(())
+        );
+        assert_eq!(
+            playlist(
+                &format!("{}\n#EXT-X-INDEPENDENT-SEGMENTS", header),
+                "#EXTINF:5.0,\n0.m4s"
+            ),
+            Ok(())
+        );
+
+        assert_eq!(
+            playlist(&format!("{}\n#EXT-X-PLAYLIST-TYPE:VOD", header), ""),
+            Err(ValidationError::UnfinishedVod)
+        );
+        assert_eq!(
+            playlist(&format!("{}\n#EXT-X-PLAYLIST-TYPE:LIVE", header), ""),
+            Err(ValidationError::InvalidValue("EXT-X-PLAYLIST-TYPE"))
+        );
+        assert_eq!(
+            validate_media_playlist("<html>"),
+            Err(ValidationError::NotAPlaylist)
+        );
     }
 }
@@ -0,0 +1,415 @@
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    player_response::InitialPlayerResponse,
+    source::{Track, TrackKind},
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LiveStatus {
+    IsLive,
+    IsUpcoming,
+    WasLive,
+    PostLive,
+    NotLive,
+}
+
+impl LiveStatus {
+    /// The status as yt-dlp reports it. A stream that ended is `post_live`
+    /// until YouTube has processed it into a video with a duration, and
+    /// `was_live` after.
+    pub fn from_ipr(ipr: &InitialPlayerResponse) -> Self {
+        let details = ipr.video_details.as_ref();
+        let broadcast = ipr.microformat.as_ref().and_then(|m| {
+            m.player_microformat_renderer
+                .live_broadcast_details
+                .as_ref()
+        });
+
+        match (details, broadcast) {
+            (_, Some(b)) if b.is_live_now => LiveStatus::IsLive,
+            (Some(d), _) if d.is_upcoming => LiveStatus::IsUpcoming,
+            (Some(d), b) if d.is_live_content || b.is_some() => {
+                if d.is_post_live_dvr || d.length_seconds == 0 {
+                    LiveStatus::PostLive
+                } else {
+                    LiveStatus::WasLive
+                }
+            }
+            _ => LiveStatus::NotLive,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Thumbnail {
+    pub url: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Format {
+    pub format_id: String,
+    pub ext: String,
+    pub vcodec: String,
+    pub acodec: String,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub fps: Option<f64>,
+    /// Total bitrate in kbit/s
+    pub tbr: f64,
+}
+
+impl From<&Track> for Format {
+    fn from(t: &Track) -> Self {
+        let (vcodec, acodec) = match t.kind {
+            TrackKind::Audio => ("none".into(), t.codecs.clone()),
+            TrackKind::Video => (t.codecs.clone(), "none".into()),
+            TrackKind::Muxed => {
+                let (v, a) = t.codecs.split_once(',').unwrap_or((&t.codecs, "none"));
+                (v.to_string(), a.to_string())
+            }
+        };
+        Self {
+            format_id: t.id.to_string(),
+            ext: t.extension().into(),
+            vcodec,
+            acodec,
+            width: t.width,
+            height: t.height,
+            fps: t.frame_rate,
+            tbr: t.bandwidth as f64 / 1000.0,
+        }
+    }
+}
+
+/// A run of consecutive segments that could not be downloaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Gap {
+    pub first_segment: i64,
+    pub last_segment: i64,
+}
+
+/// A value of an editable field, and when it was first seen.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    pub time: DateTime<Utc>,
+    pub value: String,
+}
+
+/// Metadata sidecar written next to the downloaded fragments. The layout
+/// follows yt-dlp's info JSON so existing tooling can read it, with fields
+/// specific to live archiving kept under `_yta` keys.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InfoJson {
+    pub id: String,
+    pub title: String,
+    pub fulltitle: String,
+    pub description: String,
+    pub channel: String,
+    pub channel_id: String,
+    pub channel_url: String,
+    pub uploader: String,
+    pub uploader_url: Option<String>,
+    pub webpage_url: String,
+    pub thumbnail: Option<String>,
+    pub thumbnails: Vec<Thumbnail>,
+    pub upload_date: Option<String>,
+    pub release_timestamp: Option<i64>,
+    pub view_count: i64,
+    pub live_status: LiveStatus,
+    pub is_live: bool,
+    pub was_live: bool,
+    pub format_id: Option<String>,
+    pub requested_formats: Vec<Format>,
+    pub extractor: String,
+    pub extractor_key: String,
+    pub epoch: i64,
+
+    #[serde(rename = "_yta_actual_start_time")]
+    pub actual_start_time: Option<DateTime<Utc>>,
+    #[serde(rename = "_yta_actual_end_time")]
+    pub actual_end_time: Option<DateTime<Utc>>,
+    #[serde(rename = "_yta_first_segment")]
+    pub first_segment: Option<i64>,
+    #[serde(rename = "_yta_last_segment")]
+    pub last_segment: Option<i64>,
+    #[serde(rename = "_yta_gaps")]
+    pub gaps: Vec<Gap>,
+    #[serde(rename = "_yta_bytes_downloaded")]
+    pub bytes_downloaded: u64,
+    #[serde(rename = "_yta_title_history")]
+    pub title_history: Vec<Revision>,
+    #[serde(rename = "_yta_description_history")]
+    pub description_history: Vec<Revision>,
+    #[serde(rename = "_yta_player_response")]
+    pub player_response: InitialPlayerResponse,
+}
+
+/// Converts a `YYYY-MM-DD` date, optionally followed by a time, to yt-dlp's
+/// `YYYYMMDD` format.
+fn to_ytdlp_date(date: &str) -> Option<String> {
+    let date = date.get(..10)?;
+    let out = date.replace('-', "");
+    if out.len() == 8 && out.chars().all(|c| c.is_ascii_digit()) {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+impl InfoJson {
+    pub fn from_ipr(ipr: &InitialPlayerResponse) -> Self {
+        let details = ipr.video_details.as_ref();
+        let microformat = ipr
+            .microformat
+            .as_ref()
+            .map(|m| &m.player_microformat_renderer);
+        let broadcast = microformat.and_then(|m| m.live_broadcast_details.as_ref());
+
+        let id = details.map(|d| d.video_id.clone()).unwrap_or_default();
+        let title = details.map(|d| d.title.clone()).unwrap_or_default();
+        let description = details
+            .map(|d| d.short_description.clone())
+            .unwrap_or_default();
+        let now = Utc::now();
+        let channel_id = details.map(|d| d.channel_id.clone()).unwrap_or_default();
+        let author = details.map(|d| d.author.clone()).unwrap_or_default();
+        let thumbnails = microformat
+            .map(|m| {
+                m.thumbnail
+                    .thumbnails
+                    .iter()
+                    .map(|t| Thumbnail { url: t.url.clone() })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let live_status = LiveStatus::from_ipr(ipr);
+
+        Self {
+            webpage_url: format!("https://www.youtube.com/watch?v={}", id),
+            id,
+            fulltitle: title.clone(),
+            title_history: vec![Revision {
+                time: now,
+                value: title.clone(),
+            }],
+            title,
+            description_history: vec![Revision {
+                time: now,
+                value: description.clone(),
+            }],
+            description,
+            channel_url: format!("https://www.youtube.com/channel/{}", channel_id),
+            channel_id,
+            channel: author.clone(),
+            uploader: author,
+            uploader_url: microformat.map(|m| m.owner_profile_url.clone()),
+            thumbnail: thumbnails.last().map(|t| t.url.clone()),
+            thumbnails,
+            upload_date: microformat.and_then(|m| to_ytdlp_date(&m.upload_date)),
+            release_timestamp: broadcast
+                .and_then(|b| DateTime::parse_from_rfc3339(&b.start_timestamp).ok())
+                .map(|t| t.timestamp()),
+            view_count: details.map(|d| d.view_count).unwrap_or_default(),
+            is_live: live_status == LiveStatus::IsLive,
+            was_live: matches!(live_status, LiveStatus::WasLive | LiveStatus::PostLive),
+            live_status,
+            format_id: None,
+            requested_formats: Vec::new(),
+            extractor: "youtube".into(),
+            extractor_key: "Youtube".into(),
+            epoch: now.timestamp(),
+
+            actual_start_time: broadcast
+                .and_then(|b| DateTime::parse_from_rfc3339(&b.start_timestamp).ok())
+                .map(|t| t.with_timezone(&Utc)),
+            actual_end_time: None,
+            first_segment: None,
+            last_segment: None,
+            gaps: Vec::new(),
+            bytes_downloaded: 0,
+            player_response: ipr.clone(),
+        }
+    }
+
+    /// Updates the title and description from a newer player response,
+    /// recording any changes. Returns whether anything changed.
+    pub fn update_from_ipr(&mut self, ipr: &InitialPlayerResponse) -> bool {
+        let details = match ipr.video_details.as_ref() {
+            Some(d) => d,
+            None => return false,
+        };
+        let now = Utc::now();
+        let mut changed = false;
+
+        if details.title != self.title {
+            info!("Title changed to {:?}", details.title);
+            self.title = details.title.clone();
+            self.fulltitle = details.title.clone();
+            self.title_history.push(Revision {
+                time: now,
+                value: details.title.clone(),
+            });
+            changed = true;
+        }
+        if details.short_description != self.description {
+            info!("Description changed");
+            self.description = details.short_description.clone();
+            self.description_history.push(Revision {
+                time: now,
+                value: details.short_description.clone(),
+            });
+            changed = true;
+        }
+
+        self.view_count = details.view_count;
+        self.player_response = ipr.clone();
+
+        changed
+    }
+
+    /// The title at the time the archive was started.
+    pub fn original_title(&self) -> &str {
+        self.title_history
+            .first()
+            .map(|r| r.value.as_str())
+            .unwrap_or(&self.title)
+    }
+
+    /// Records the selected formats, video first as yt-dlp does.
+    pub fn set_formats(&mut self, tracks: &[Track]) {
+        let mut tracks = tracks.iter().collect::<Vec<_>>();
+        tracks.sort_by_key(|t| t.kind == TrackKind::Audio);
+
+        self.format_id = Some(
+            tracks
+                .iter()
+                .map(|t| t.id.to_string())
+                .collect::<Vec<_>>()
+                .join("+"),
+        );
+        self.requested_formats = tracks.into_iter().map(Format::from).collect();
+    }
+
+    /// Records a failed segment, merging it into an adjacent gap if possible.
+    pub fn add_gap(&mut self, seq: i64) {
+        match self.gaps.last_mut() {
+            Some(gap) if gap.last_segment + 1 == seq => gap.last_segment = seq,
+            _ => self.gaps.push(Gap {
+                first_segment: seq,
+                last_segment: seq,
+            }),
+        }
+    }
+
+    pub async fn write(&self, path: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        let temp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, json).await?;
+        tokio::fs::rename(temp_path, path).await
+    }
+
+    pub async fn read(path: &Path) -> std::io::Result<Self> {
+        let json = tokio::fs::read(path).await?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_ipr(fname: &str) -> InitialPlayerResponse {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/");
+        d.push(fname);
+        let html =
+            std::fs::read_to_string(d).unwrap_or_else(|_| panic!("Could not read {}", fname));
+        InitialPlayerResponse::from_html(&html).expect("Could not parse IPR")
+    }
+
+    #[test]
+    fn live_status() {
+        let info = InfoJson::from_ipr(&get_test_ipr("watchpage_live.html"));
+        assert_eq!(info.live_status, LiveStatus::IsLive);
+        assert_eq!(info.id, "jfKfPfyJRdk");
+        assert_eq!(info.upload_date.as_deref(), Some("20220712"));
+        assert_eq!(info.release_timestamp, Some(1657641570));
+
+        let info = InfoJson::from_ipr(&get_test_ipr("watchpage_scheduled.html"));
+        assert_eq!(info.live_status, LiveStatus::IsUpcoming);
+
+        // Ended and processed into a video
+        let ipr = get_test_ipr("watchpage_post_live.html");
+        let info = InfoJson::from_ipr(&ipr);
+        assert_eq!(info.live_status, LiveStatus::WasLive);
+        assert!(info.was_live);
+
+        // Ended, but still processing
+        let mut processing = ipr.clone();
+        let details = processing.video_details.as_mut().unwrap();
+        details.length_seconds = 0;
+        details.is_post_live_dvr = true;
+        let info = InfoJson::from_ipr(&processing);
+        assert_eq!(info.live_status, LiveStatus::PostLive);
+        assert!(info.was_live);
+
+        // Processing is also told by the missing duration alone
+        processing.video_details.as_mut().unwrap().is_post_live_dvr = false;
+        assert_eq!(LiveStatus::from_ipr(&processing), LiveStatus::PostLive);
+
+        let mut not_live = ipr;
+        not_live.video_details.as_mut().unwrap().is_live_content = false;
+        not_live
+            .microformat
+            .as_mut()
+            .unwrap()
+            .player_microformat_renderer
+            .live_broadcast_details = None;
+        assert_eq!(LiveStatus::from_ipr(&not_live), LiveStatus::NotLive);
+    }
+
+    #[test]
+    fn gaps() {
+        let mut info = InfoJson::from_ipr(&get_test_ipr("watchpage_live.html"));
+        for seq in [3, 4, 5, 9, 11, 12] {
+            info.add_gap(seq);
+        }
+        let gaps = info
+            .gaps
+            .iter()
+            .map(|g| (g.first_segment, g.last_segment))
+            .collect::<Vec<_>>();
+        assert_eq!(gaps, vec![(3, 5), (9, 9), (11, 12)]);
+    }
+
+    #[test]
+    fn title_history() {
+        let ipr = get_test_ipr("watchpage_live.html");
+        let mut info = InfoJson::from_ipr(&ipr);
+        assert!(!info.update_from_ipr(&ipr));
+
+        let mut renamed = ipr.clone();
+        renamed.video_details.as_mut().unwrap().title = "New title".into();
+        assert!(info.update_from_ipr(&renamed));
+        assert!(!info.update_from_ipr(&renamed));
+
+        assert_eq!(info.title, "New title");
+        assert_eq!(info.original_title(), ipr.video_details.unwrap().title);
+        assert_eq!(info.title_history.len(), 2);
+        assert_eq!(info.description_history.len(), 1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let info = InfoJson::from_ipr(&get_test_ipr("watchpage_scheduled.html"));
+        let json = serde_json::to_string(&info).expect("Could not serialize");
+        let parsed: InfoJson = serde_json::from_str(&json).expect("Could not deserialize");
+        assert_eq!(info, parsed);
+    }
+}
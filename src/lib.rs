@@ -1,8 +1,9 @@
 //! # yta-rs
 //!
-//! This crate provides a library for downloading YouTube DASH live streams. It
-//! is based on [Kethsar/ytarchive](https://github.com/Kethsar/ytarchive), but
-//! is more stripped down and geared towards being a library.
+//! This crate provides a library for downloading YouTube live streams over
+//! DASH, or HLS when DASH is not available. It is based on
+//! [Kethsar/ytarchive](https://github.com/Kethsar/ytarchive), but is more
+//! stripped down and geared towards being a library.
 //!
 //! ## Usage
 //!
@@ -11,7 +12,7 @@
 //! the initial player response and start downloading segments using the
 //! `worker` module.
 //!
-//! ```rust
+//! ```rust,no_run
 //! use yta_rs::{player_response::InitialPlayerResponse, util, worker};
 //!
 //! #[tokio::main]
@@ -36,22 +37,40 @@
 //!
 //!     // Start the worker
 //!     let workdir = std::path::Path::new(".");
-//!     worker::start(&client, &ipr, workdir).await.unwrap();
+//!     let config = worker::WorkerConfig::default();
+//!     let cancel = tokio_util::sync::CancellationToken::new();
+//!     worker::start(&client, &ipr, workdir, &config, cancel, None).await.unwrap();
 //! }
 //! ```
 //!
 //! The `worker` module provides a `start` function that will download segments
 //! and write them to disk. It will also write an `index.m3u8` file that can be
-//! used to play the stream.
+//! used to play the stream, and an `info.json` file with the stream metadata.
+//! Streams that have already ended can be downloaded with `vod::download`.
 
-#[forbid(unsafe_code)]
+#![forbid(unsafe_code)]
 #[macro_use]
 extern crate log;
 
+pub mod channel;
+pub mod cipher;
+pub mod config;
 pub mod dash;
+pub mod event;
+pub mod feed;
 pub mod ffmpeg;
 pub mod hls;
+pub mod info;
+pub mod metrics;
+pub mod monitor;
 pub mod player_response;
+pub mod serve;
+pub mod source;
 pub mod stats;
+pub mod storyboard;
+pub mod subtitle;
+pub mod target;
+pub mod template;
 pub mod util;
+pub mod vod;
 pub mod worker;
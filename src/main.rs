@@ -1,11 +1,35 @@
 #[macro_use]
 extern crate log;
 
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand};
 use tokio::{
     select,
     signal::unix::{signal, SignalKind},
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_util::sync::CancellationToken;
+use yta_rs::{
+    channel::{self, Channel},
+    config::{self, Config},
+    event::{Event, EventKind, EventSender},
+    ffmpeg, info, metrics, monitor,
+    player_response::InitialPlayerResponse,
+    serve,
+    source::{FormatSelection, SourceKind},
+    stats,
+    subtitle::{self, SubtitleFormat},
+    target::{Target, TargetError},
+    template::Template,
+    util, vod, worker,
 };
-use yta_rs::{ffmpeg, player_response::InitialPlayerResponse, util, worker};
 
 #[derive(thiserror::Error, Debug)]
 enum RunError {
@@ -13,131 +37,920 @@ enum RunError {
     SignalInterrupt(#[from] std::io::Error),
     #[error("Worker error")]
     WorkerError(#[from] worker::WorkerError),
+    #[error("VOD download error")]
+    VodError(#[from] vod::VodError),
     #[error("Mux error")]
     MuxError(#[from] ffmpeg::FfmpegError),
+    #[error("Monitor error")]
+    MonitorError(#[from] monitor::MonitorError),
+    #[error("{0}")]
+    InvalidTarget(#[from] TargetError),
+    #[error("Cancelled")]
+    Cancelled,
     #[error("Error")]
     Error(String, Box<dyn std::error::Error>),
 }
 
-async fn run(url: String) -> Result<(), RunError> {
-    // Initialize env_logger
-    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+/// Archive YouTube livestreams and videos
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Log filter, e.g. `debug` or `yta_rs=trace`
+    #[arg(long, global = true, env = "RUST_LOG", default_value = "info")]
+    log_level: String,
+    /// TOML config file with defaults for all options [default:
+    /// ~/.config/yta-rs/config.toml]
+    #[arg(short, long, global = true, env = "YTA_CONFIG")]
+    config: Option<PathBuf>,
+    /// Netscape cookies.txt file sent with every request
+    #[arg(long, global = true, env = "YTA_COOKIES")]
+    cookies: Option<PathBuf>,
+    /// Proxy for all requests, e.g. `socks5://127.0.0.1:1080`
+    #[arg(long, global = true, env = "YTA_PROXY")]
+    proxy: Option<String>,
+    /// Serve Prometheus metrics on this address, e.g. `127.0.0.1:9090`
+    #[arg(long, global = true, env = "YTA_METRICS")]
+    metrics: Option<SocketAddr>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download a live stream or finished video
+    Download {
+        /// Video URL or ID, or a channel URL or handle to download its
+        /// current stream
+        target: Target,
+        #[command(flatten)]
+        args: DownloadArgs,
+    },
+    /// Wait for an upcoming stream to start, then download it
+    Wait {
+        target: Target,
+        /// Seconds between checks
+        #[arg(long, default_value_t = 60)]
+        interval: u64,
+        #[command(flatten)]
+        args: DownloadArgs,
+    },
+    /// Watch channels and archive every stream they start
+    Monitor {
+        /// Channel URLs, IDs or handles, instead of those in the config file
+        channels: Vec<String>,
+        /// Seconds between checks of each channel [default: 60]
+        #[arg(long)]
+        interval: Option<u64>,
+        /// Maximum number of streams downloaded at the same time [default: 4]
+        #[arg(long, env = "YTA_MAX_CONCURRENT")]
+        max_concurrent: Option<usize>,
+        /// Directory the working directories and outputs are placed in
+        /// [default: .]
+        #[arg(long, env = "YTA_OUTDIR")]
+        outdir: Option<PathBuf>,
+        #[command(flatten)]
+        output: OutputArgs,
+        #[command(flatten)]
+        worker: WorkerArgs,
+    },
+    /// Mux a working directory that was already downloaded
+    Mux {
+        workdir: PathBuf,
+        /// Output file template
+        #[arg(short, long, env = "YTA_OUTPUT")]
+        output: Option<Template>,
+        #[command(flatten)]
+        chat: ChatArgs,
+    },
+    /// Print the metadata of a video as JSON
+    Info { target: Target },
+    /// Serve a player for the streams in a directory, to watch them in a
+    /// browser while they download
+    Serve {
+        /// A working directory, or a directory containing several
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+        /// Address to listen on
+        #[arg(short, long, env = "YTA_LISTEN", default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+    },
+}
 
-    // Create HttpClient
-    let client = util::HttpClient::new().expect("Could not create HttpClient");
+#[derive(Args, Debug)]
+struct OutputArgs {
+    /// Working directory template, e.g. `{channel}/{id}` [default: {id}]
+    #[arg(short, long, env = "YTA_WORKDIR")]
+    workdir: Option<Template>,
+    /// Output file template [default: "{start_date} {title} [{id}].mp4"]
+    #[arg(short, long, env = "YTA_OUTPUT")]
+    output: Option<Template>,
+}
 
-    // Fetch the URL
-    info!("Fetching {}", url);
-    let html = client
-        .fetch_text(&url)
+impl OutputArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(workdir) = &self.workdir {
+            config.workdir = workdir.clone();
+        }
+        if let Some(output) = &self.output {
+            config.output = output.clone();
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct WorkerArgs {
+    /// `best`, `worst`, a maximum height such as `720p`, or itags such as
+    /// `299+140` [default: best]
+    #[arg(short, long, env = "YTA_FORMAT")]
+    format: Option<FormatSelection>,
+    /// Manifest live segments are downloaded from, `dash` or `hls`
+    /// [default: dash]
+    #[arg(long, env = "YTA_SOURCE")]
+    source: Option<SourceKind>,
+    /// Number of segments, or chunks of each format of a finished video,
+    /// downloaded at the same time [default: 4]
+    #[arg(short = 'j', long, env = "YTA_CONCURRENCY")]
+    concurrency: Option<usize>,
+    /// Seconds between checks for new segments [default: 1]
+    #[arg(long, env = "YTA_POLL_INTERVAL", value_parser = parse_secs)]
+    poll_interval: Option<Duration>,
+    /// Stop after no new segments appear for this many seconds [default: 30]
+    #[arg(long, env = "YTA_TIMEOUT", value_parser = parse_secs)]
+    timeout: Option<Duration>,
+    /// Download the storyboard sprite sheets, for seek previews
+    #[arg(long)]
+    storyboard: bool,
+}
+
+fn parse_secs(s: &str) -> Result<Duration, String> {
+    let secs = s.parse::<f64>().map_err(|e| e.to_string())?;
+    Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
+}
+
+impl WorkerArgs {
+    fn apply(&self, config: &mut worker::WorkerConfig) {
+        if let Some(format) = &self.format {
+            config.format = format.clone();
+        }
+        if let Some(source) = self.source {
+            config.source = source;
+        }
+        if let Some(concurrency) = self.concurrency {
+            config.concurrency = concurrency;
+        }
+        if let Some(interval) = self.poll_interval {
+            config.poll_interval = interval;
+        }
+        if let Some(timeout) = self.timeout {
+            config.no_segment_timeout = timeout;
+        }
+        if self.storyboard {
+            config.storyboard = true;
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct DownloadArgs {
+    #[command(flatten)]
+    output: OutputArgs,
+    #[command(flatten)]
+    worker: WorkerArgs,
+    /// Delete fragments and playlists after muxing
+    #[arg(long)]
+    delete_fragments: bool,
+    /// Leave the index at the end of the output instead of moving it to the
+    /// start, which is faster but prevents playback before fully loaded
+    #[arg(long)]
+    no_faststart: bool,
+    /// Serve the working directory on this address while a live stream
+    /// downloads, e.g. `127.0.0.1:8080`
+    #[arg(long, env = "YTA_SERVE")]
+    serve: Option<SocketAddr>,
+    #[command(flatten)]
+    chat: ChatArgs,
+}
+
+#[derive(Args, Debug)]
+struct ChatArgs {
+    /// Chat replay to add as a subtitle track when muxing to MKV, such as
+    /// yt-dlp's live_chat.json [default: live_chat.json in the working
+    /// directory, if it exists]
+    #[arg(long)]
+    chat: Option<PathBuf>,
+    /// Subtitle format for the chat: ass (scrolling) or vtt
+    #[arg(long, default_value = "ass")]
+    chat_format: SubtitleFormat,
+}
+
+impl ChatArgs {
+    /// Exports the chat next to the fragments, returning the subtitle track
+    async fn export(
+        &self,
+        workdir: &Path,
+        playlist: &str,
+        info: &info::InfoJson,
+    ) -> Option<PathBuf> {
+        subtitle::chat_track(
+            self.chat.as_deref(),
+            workdir,
+            playlist,
+            info,
+            self.chat_format,
+        )
+        .await
+    }
+}
+
+impl DownloadArgs {
+    fn apply(&self, config: &mut Config) {
+        self.output.apply(config);
+        self.worker.apply(&mut config.worker);
+    }
+}
+
+/// Reads the config file given on the command line, or the default one if it
+/// exists. Options from the environment variables in `vars` are applied on
+/// top of it, and then the global options.
+async fn load_config(
+    cli: &Cli,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Config, RunError> {
+    let path = match &cli.config {
+        Some(path) => Some(path.clone()),
+        None => config::default_path().filter(|p| p.exists()),
+    };
+    if let Some(path) = &path {
+        debug!("Reading config from {}", path.display());
+    }
+    let mut config = Config::load_with_env(path.as_deref(), vars)
         .await
-        .map_err(|e| RunError::Error("Could not fetch URL".to_string(), Box::new(e)))?;
+        .map_err(|e| {
+            let source = path.as_deref().unwrap_or(Path::new("environment"));
+            RunError::Error(format!("Could not load {}", source.display()), Box::new(e))
+        })?;
 
-    // Parse the HTML
-    info!("Parsing initial player response");
-    let ipr =
-        InitialPlayerResponse::from_html(html.as_str()).expect("Could not parse player response");
+    if let Some(proxy) = &cli.proxy {
+        config.http.proxy = Some(proxy.clone());
+    }
+    if let Some(cookies) = &cli.cookies {
+        config.http.cookies = Some(cookies.clone());
+    }
+    if let Some(metrics) = cli.metrics {
+        config.metrics = Some(metrics);
+    }
+
+    Ok(config)
+}
 
-    // Check if is live
+async fn bind(addr: SocketAddr) -> Result<tokio::net::TcpListener, RunError> {
+    tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(|e| RunError::Error(format!("Could not listen on {}", addr), Box::new(e)))
+}
+
+fn http_client(config: &Config) -> Result<util::HttpClient, RunError> {
+    util::HttpClient::with_options(&config.http)
+        .map_err(|e| RunError::Error("Could not create HttpClient".to_string(), Box::new(e)))
+}
+
+/// Fetches the player response of a video, or of the current stream of a
+/// channel. Returns `None` if the channel is not live.
+async fn resolve(
+    client: &util::HttpClient,
+    target: &Target,
+) -> Result<Option<InitialPlayerResponse>, RunError> {
+    match target {
+        Target::Channel(channel) => {
+            // Find the current stream of the channel
+            info!("Looking for a live stream on {}", channel);
+            let live = channel::find_live(client, channel)
+                .await
+                .map_err(|e| RunError::Error("Could not fetch channel".to_string(), Box::new(e)))?;
+            Ok(live.map(|(video_id, ipr)| {
+                info!("Found stream {}", video_id);
+                ipr
+            }))
+        }
+        Target::Video(video_id) => {
+            info!("Fetching {}", target.url());
+            InitialPlayerResponse::fetch(client, video_id)
+                .await
+                .map(Some)
+                .map_err(|e| {
+                    RunError::Error("Could not get player response".to_string(), Box::new(e))
+                })
+        }
+    }
+}
+
+/// Runs a step that leaves nothing worth keeping when interrupted, returning
+/// as soon as `cancel` is triggered.
+async fn until_cancelled<T>(
+    cancel: &CancellationToken,
+    fut: impl Future<Output = Result<T, RunError>>,
+) -> Result<T, RunError> {
+    select! {
+        res = fut => res,
+        _ = cancel.cancelled() => Err(RunError::Cancelled),
+    }
+}
+
+/// Creates the channel downloads publish their events to. Progress is shown
+/// on the terminal, and metrics are served if enabled in the config.
+async fn events(config: &Config, client: &util::HttpClient) -> Result<EventSender, RunError> {
+    let (tx, rx) = broadcast::channel(64);
+    show_progress(rx);
+
+    if let Some(addr) = config.metrics {
+        let metrics = metrics::Metrics::new(Some(client.statuses.clone()))
+            .map_err(|e| RunError::Error("Could not create metrics".to_string(), Box::new(e)))?;
+        let listener = bind(addr).await?;
+
+        let metrics = Arc::new(metrics);
+        let rx = tx.subscribe();
+        let listen = metrics.clone();
+        tokio::spawn(async move { listen.listen(rx).await });
+        tokio::spawn(async move {
+            // Keep serving while the last downloads finish after a signal
+            if let Err(e) = metrics::serve(listener, metrics, CancellationToken::new()).await {
+                error!("{}", e);
+            }
+        });
+    }
+
+    Ok(tx)
+}
+
+/// Shows the progress of downloads on the terminal, until every sender of
+/// the channel is dropped.
+fn show_progress(mut rx: broadcast::Receiver<Event>) {
+    tokio::spawn(async move {
+        let printer = stats::ProgressPrinter::new();
+        loop {
+            match rx.recv().await {
+                Ok(Event {
+                    video_id,
+                    kind: EventKind::Progress(snapshot),
+                    ..
+                }) => printer.print(&video_id, &snapshot),
+                Ok(Event {
+                    kind: EventKind::Finished { .. } | EventKind::Failed { .. },
+                    ..
+                }) => printer.finish(),
+                Ok(_) | Err(RecvError::Lagged(_)) => (),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+async fn run_download(
+    client: &util::HttpClient,
+    target: &Target,
+    config: &Config,
+    args: &DownloadArgs,
+    cancel: &CancellationToken,
+    events: &EventSender,
+) -> Result<(), RunError> {
+    let Some(ipr) = until_cancelled(cancel, resolve(client, target)).await? else {
+        error!("Channel is not live");
+        return Ok(());
+    };
+    download(client, ipr, config, args, cancel, events).await
+}
+
+async fn run_wait(
+    client: &util::HttpClient,
+    target: &Target,
+    interval: Duration,
+    config: &Config,
+    args: &DownloadArgs,
+    cancel: &CancellationToken,
+    events: &EventSender,
+) -> Result<(), RunError> {
+    let ipr = until_cancelled(cancel, wait(client, target, interval)).await?;
+    match ipr {
+        Some(ipr) => download(client, ipr, config, args, cancel, events).await,
+        None => {
+            error!("Stream is no longer upcoming, or cannot be played");
+            Ok(())
+        }
+    }
+}
+
+/// Waits for the video, or the next stream of the channel, to go live.
+async fn wait(
+    client: &util::HttpClient,
+    target: &Target,
+    interval: Duration,
+) -> Result<Option<InitialPlayerResponse>, RunError> {
+    // A channel may only list its stream shortly before it starts
+    let video_id = match target {
+        Target::Video(video_id) => video_id.clone(),
+        Target::Channel(channel) => loop {
+            info!("Looking for a stream on {}", channel);
+            let live = channel::find_live(client, channel)
+                .await
+                .map_err(|e| RunError::Error("Could not fetch channel".to_string(), Box::new(e)))?;
+            if let Some((video_id, _)) = live {
+                break video_id;
+            }
+            tokio::time::sleep(interval).await;
+        },
+    };
+
+    info!("Waiting for {} to start", video_id);
+    monitor::wait_for_live(client, &video_id, interval)
+        .await
+        .map_err(|e| RunError::Error("Could not get player response".to_string(), Box::new(e)))
+}
+
+/// Downloads and muxes a live stream or finished video. A live stream that
+/// is cancelled is still muxed up to the point it was stopped.
+async fn download(
+    client: &util::HttpClient,
+    ipr: InitialPlayerResponse,
+    config: &Config,
+    args: &DownloadArgs,
+    cancel: &CancellationToken,
+    events: &EventSender,
+) -> Result<(), RunError> {
+    // Check if is live, or has finished processing
     if ipr.is_usable() {
         info!("Video is live");
-        ipr.video_details.as_ref().map(|v| {
-            info!("[*] Title  : {}", v.title);
-            info!("[*] Channel: {}", v.author);
-        });
+    } else if ipr.is_vod() {
+        info!("Video is not live, downloading adaptive formats");
     } else {
         error!("Video is not live");
         return Ok(());
     }
+    if let Some(v) = ipr.video_details.as_ref() {
+        info!("[*] Title  : {}", v.title);
+        info!("[*] Channel: {}", v.author);
+    }
 
     // Create a working directory
-    let workdir = std::path::Path::new("yta_dl");
-    if !workdir.exists() {
-        tokio::fs::create_dir(workdir).await.map_err(|e| {
-            RunError::Error(
-                "Could not create working directory".to_string(),
-                Box::new(e),
-            )
-        })?;
+    let workdir = config.workdir.render_ipr(&ipr);
+    let workdir = workdir.as_path();
+    tokio::fs::create_dir_all(workdir).await.map_err(|e| {
+        RunError::Error(
+            "Could not create working directory".to_string(),
+            Box::new(e),
+        )
+    })?;
+
+    if !ipr.is_usable() {
+        let files = until_cancelled(cancel, async {
+            Ok(vod::download(client, &ipr, workdir, &config.worker).await?)
+        })
+        .await?;
+        let mut meta = ffmpeg::Metadata::from_info(&files.info, thumbnail_path(workdir));
+        meta.faststart = !args.no_faststart;
+        meta.subtitles = args
+            .chat
+            .export(workdir, &config.worker.playlist, &files.info)
+            .await;
+        let output = output_path(&config.output, &files.info).await?;
+        mux_and_clean(
+            &[&files.video, &files.audio],
+            &meta,
+            &output,
+            workdir,
+            &config.worker,
+            args.delete_fragments,
+        )
+        .await?;
+    } else {
+        if let Some(addr) = args.serve {
+            let listener = bind(addr).await?;
+            let workdir = workdir.to_path_buf();
+            let playlist = config.worker.playlist.clone();
+            tokio::spawn(async move {
+                // Keep serving until the process exits, after muxing
+                let cancel = CancellationToken::new();
+                if let Err(e) = serve::serve(listener, &workdir, &playlist, cancel).await {
+                    error!("Could not serve {}: {}", workdir.display(), e);
+                }
+            });
+        }
+
+        let info = worker::start(
+            client,
+            &ipr,
+            workdir,
+            &config.worker,
+            cancel.clone(),
+            Some(events),
+        )
+        .await?;
+
+        // Mux the video
+        let in_m3u8 = workdir.join(&config.worker.playlist);
+        let mut meta = ffmpeg::Metadata::from_info(&info, thumbnail_path(workdir));
+        meta.faststart = !args.no_faststart;
+        meta.subtitles = args
+            .chat
+            .export(workdir, &config.worker.playlist, &info)
+            .await;
+        let output = output_path(&config.output, &info).await?;
+        mux_and_clean(
+            &[&in_m3u8],
+            &meta,
+            &output,
+            workdir,
+            &config.worker,
+            args.delete_fragments,
+        )
+        .await?;
     }
 
-    // Write the index.html file
-    let index_path = workdir.join("index.html");
-    let html = include_bytes!("../resources/index.html");
-    tokio::fs::write(index_path, html)
+    Ok(())
+}
+
+/// Muxes `inputs` to `output`, then deletes the fragments in `workdir` if
+/// `delete` is set. The fragments are kept unless ffmpeg succeeded and the
+/// output exists, as they may be the only copy of the stream.
+async fn mux_and_clean(
+    inputs: &[&Path],
+    meta: &ffmpeg::Metadata,
+    output: &Path,
+    workdir: &Path,
+    config: &worker::WorkerConfig,
+    delete: bool,
+) -> Result<(), RunError> {
+    ffmpeg::mux_inputs(inputs, meta, output).await?;
+    if !delete {
+        return Ok(());
+    }
+
+    if !tokio::fs::try_exists(output).await.unwrap_or(false) {
+        return Err(RunError::Error(
+            format!("{} was not written", output.display()),
+            "keeping fragments".into(),
+        ));
+    }
+    delete_fragments(workdir, config)
         .await
-        .map_err(|e| RunError::Error("Could not write index.html".to_string(), Box::new(e)))?;
+        .map_err(|e| RunError::Error("Could not delete fragments".to_string(), Box::new(e)))
+}
+
+/// Renders the output file name from the final metadata, creating its parent
+/// directory if needed.
+async fn output_path(
+    template: &Template,
+    info: &info::InfoJson,
+) -> Result<std::path::PathBuf, RunError> {
+    let output = template.render(info);
+    if let Some(parent) = output.parent().filter(|p| !p.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(parent).await.map_err(|e| {
+            RunError::Error("Could not create output directory".to_string(), Box::new(e))
+        })?;
+    }
+
+    Ok(output)
+}
+
+fn is_fragment(fname: &str) -> bool {
+    fname.starts_with("seq_")
+        || fname.ends_with(".m3u8")
+        || fname.starts_with("video.f")
+        || fname.starts_with("audio.f")
+}
+
+/// Removes downloaded fragments and playlists, keeping the metadata and
+/// thumbnail.
+async fn delete_fragments(workdir: &Path, config: &worker::WorkerConfig) -> std::io::Result<()> {
+    // Never delete a directory outside the working directory
+    if let Some(segment_dir) = config
+        .segment_dir
+        .as_deref()
+        .filter(|d| util::is_subpath(d))
+    {
+        let segment_dir = workdir.join(segment_dir);
+        if segment_dir.is_dir() && segment_dir != workdir {
+            tokio::fs::remove_dir_all(segment_dir).await?;
+        }
+    }
 
-    worker::start(&client, &ipr, workdir)
+    let mut entries = tokio::fs::read_dir(workdir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if is_fragment(&entry.file_name().to_string_lossy()) {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Finds the video and audio formats downloaded by `vod::download`.
+async fn vod_inputs(workdir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut inputs = Vec::new();
+    for prefix in ["video.f", "audio.f"] {
+        let mut entries = tokio::fs::read_dir(workdir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let fname = entry.file_name().to_string_lossy().into_owned();
+            if fname.starts_with(prefix) && !fname.ends_with(".tmp") {
+                inputs.push(entry.path());
+                break;
+            }
+        }
+    }
+    Ok(inputs)
+}
+
+async fn run_mux(
+    workdir: &Path,
+    output: &Template,
+    playlist: &str,
+    chat: &ChatArgs,
+) -> Result<(), RunError> {
+    let info = info::InfoJson::read(&workdir.join("info.json"))
         .await
-        .map_err(RunError::WorkerError)?;
-
-    // Mux the video
-    let in_m3u8 = workdir.join("index.m3u8");
-    let thumbnail = workdir.join("thumbnail.jpg");
-    let out_mp4 = workdir.join("video.mp4");
-    let meta = ffmpeg::Metadata {
-        title: ipr.video_details.as_ref().map(|v| v.title.clone()),
-        video_id: ipr.video_details.as_ref().map(|v| v.video_id.clone()),
-        date: ipr
-            .microformat
-            .as_ref()
-            .map(|m| m.player_microformat_renderer.publish_date.clone()),
-        description: ipr
-            .video_details
-            .as_ref()
-            .map(|v| v.short_description.clone()),
-        thumbnail: if thumbnail.exists() {
-            Some(thumbnail)
-        } else {
-            None
-        },
-        faststart: true,
+        .map_err(|e| RunError::Error("Could not read info.json".to_string(), Box::new(e)))?;
+    let mut meta = ffmpeg::Metadata::from_info(&info, thumbnail_path(workdir));
+    meta.subtitles = chat.export(workdir, playlist, &info).await;
+    let output = output_path(output, &info).await?;
+
+    let playlist = workdir.join(playlist);
+    if playlist.exists() {
+        return Ok(ffmpeg::mux(&playlist, &meta, &output).await?);
+    }
+
+    // Separate formats of a VOD download, ignoring unfinished ones
+    let inputs = vod_inputs(workdir).await.map_err(|e| {
+        RunError::Error("Could not read working directory".to_string(), Box::new(e))
+    })?;
+    if inputs.is_empty() {
+        return Err(RunError::Error(
+            format!("Nothing to mux in {}", workdir.display()),
+            "expected a playlist or downloaded formats".into(),
+        ));
+    }
+
+    let inputs = inputs.iter().map(|p| p.as_path()).collect::<Vec<_>>();
+    Ok(ffmpeg::mux_inputs(&inputs, &meta, &output).await?)
+}
+
+async fn run_info(client: &util::HttpClient, target: &Target) -> Result<(), RunError> {
+    let Some(ipr) = resolve(client, target).await? else {
+        error!("Channel is not live");
+        return Ok(());
     };
-    ffmpeg::mux(&in_m3u8, &meta, &out_mp4)
-        .await
-        .map_err(RunError::MuxError)
+    let info = info::InfoJson::from_ipr(&ipr);
+    let json = serde_json::to_string_pretty(&info)
+        .map_err(|e| RunError::Error("Could not serialize info".to_string(), Box::new(e)))?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// Monitor options from the config file, with channels and options given on
+/// the command line taking precedence.
+fn monitor_config(
+    config: &Config,
+    channels: &[String],
+    interval: Option<u64>,
+    max_concurrent: Option<usize>,
+    outdir: Option<&Path>,
+) -> Result<monitor::MonitorConfig, RunError> {
+    let mut monitor = config.monitor_config();
+    if !channels.is_empty() {
+        monitor.channels = channels
+            .iter()
+            .map(|c| {
+                Channel::parse(c).ok_or_else(|| {
+                    RunError::Error(
+                        format!("Invalid channel: {}", c),
+                        "expected a channel URL, ID or handle".into(),
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+    }
+    if monitor.channels.is_empty() {
+        return Err(RunError::Error(
+            "No channels to monitor".to_string(),
+            "give channels as arguments or in the config file".into(),
+        ));
+    }
+
+    if let Some(interval) = interval {
+        monitor.poll_interval = Duration::from_secs(interval);
+    }
+    if let Some(max_concurrent) = max_concurrent {
+        monitor.max_concurrent = max_concurrent;
+    }
+    if let Some(outdir) = outdir {
+        monitor.outdir = outdir.to_path_buf();
+    }
+
+    Ok(monitor)
+}
+
+async fn run(cli: Cli, cancel: CancellationToken) -> Result<(), RunError> {
+    let vars = std::env::vars_os()
+        .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)));
+    let mut config = load_config(&cli, vars).await?;
+
+    match &cli.command {
+        Command::Download { target, args } => {
+            args.apply(&mut config);
+            let client = http_client(&config)?;
+            let events = events(&config, &client).await?;
+            run_download(&client, target, &config, args, &cancel, &events).await
+        }
+        Command::Wait {
+            target,
+            interval,
+            args,
+        } => {
+            args.apply(&mut config);
+            let interval = Duration::from_secs(*interval);
+            let client = http_client(&config)?;
+            let events = events(&config, &client).await?;
+            run_wait(&client, target, interval, &config, args, &cancel, &events).await
+        }
+        Command::Monitor {
+            channels,
+            interval,
+            max_concurrent,
+            outdir,
+            output,
+            worker,
+        } => {
+            output.apply(&mut config);
+            worker.apply(&mut config.worker);
+            let monitor = monitor_config(
+                &config,
+                channels,
+                *interval,
+                *max_concurrent,
+                outdir.as_deref(),
+            )?;
+            let client = http_client(&config)?;
+            let events = events(&config, &client).await?;
+            Ok(monitor::run(Arc::new(client), monitor, cancel, Some(events)).await?)
+        }
+        Command::Mux {
+            workdir,
+            output,
+            chat,
+        } => {
+            let output = output.as_ref().unwrap_or(&config.output);
+            until_cancelled(
+                &cancel,
+                run_mux(workdir, output, &config.worker.playlist, chat),
+            )
+            .await
+        }
+        Command::Info { target } => {
+            until_cancelled(&cancel, run_info(&http_client(&config)?, target)).await
+        }
+        Command::Serve { dir, listen } => {
+            serve::serve(bind(*listen).await?, dir, &config.worker.playlist, cancel)
+                .await
+                .map_err(|e| {
+                    RunError::Error(format!("Could not serve {}", dir.display()), Box::new(e))
+                })
+        }
+    }
+}
+
+fn thumbnail_path(workdir: &std::path::Path) -> Option<std::path::PathBuf> {
+    Some(workdir.join("thumbnail.jpg")).filter(|p| p.exists())
 }
 
 #[tokio::main]
 async fn main() {
-    // Read url from args
-    let url = std::env::args().nth(1).expect("No url provided");
+    let cli = Cli::parse();
 
-    let (stop_tx, mut stop_rx) = tokio::sync::watch::channel(());
+    // Initialize env_logger
+    env_logger::Builder::new()
+        .parse_filters(&cli.log_level)
+        .init();
 
-    let signal_process = tokio::spawn(async move {
-        let mut sigterm = signal(SignalKind::terminate()).unwrap();
-        let mut sigint = signal(SignalKind::interrupt()).unwrap();
-        loop {
-            select! {
-                _ = sigterm.recv() => info!("Recieve SIGTERM"),
-                _ = sigint.recv() => info!("Recieve SIGTERM"),
-            };
-            stop_tx.send(()).unwrap();
-        }
-    });
+    let cancel = CancellationToken::new();
+    let signal_process = tokio::spawn(handle_signals(cancel.clone()));
 
+    select! {
+        res = run(cli, cancel) => match res {
+            Ok(()) => info!("Worker process exited"),
+            Err(RunError::Cancelled) => {
+                warn!("Interrupted");
+                std::process::exit(130);
+            }
+            Err(e) => {
+                error!("Worker error: {:#?}", e);
+                std::process::exit(1);
+            }
+        },
+        _ = signal_process => {
+            warn!("Signal process exited");
+            std::process::exit(1);
+        },
+    }
+}
+
+/// Stops gracefully on the first SIGINT or SIGTERM, and immediately on the
+/// second.
+async fn handle_signals(cancel: CancellationToken) {
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
     loop {
         select! {
-            biased;
+            _ = sigterm.recv() => info!("Received SIGTERM"),
+            _ = sigint.recv() => info!("Received SIGINT"),
+        };
 
-            _ = stop_rx.changed() => {
-                info!("Stop signal recieved");
-                break;
-            },
-            res = run(url) => {
-                info!("Worker process exited");
-                if let Err(e) = res {
-                    error!("Worker error: {:#?}", e);
-                    std::process::exit(1);
-                }
-                break;
-            },
-            _ = signal_process => {
-                warn!("Signal process exited");
-                std::process::exit(1);
-            },
+        if cancel.is_cancelled() {
+            warn!("Stopping immediately");
+            std::process::exit(130);
         }
+        info!("Stopping after the segments in progress, send again to stop immediately");
+        cancel.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn config_precedence() {
+        let vars = vec![
+            ("YTA_HTTP__PROXY".to_string(), "http://env:8080".to_string()),
+            ("YTA_HTTP__RETRIES".to_string(), "9".to_string()),
+        ];
+        let config_path =
+            std::env::temp_dir().join(format!("yta-rs-precedence-{}.toml", std::process::id()));
+        tokio::fs::write(&config_path, "[http]\nretries = 5\ncookies = \"file.txt\"")
+            .await
+            .unwrap();
+        let cli = Cli::try_parse_from([
+            "yta-rs".as_ref(),
+            "--config".as_ref(),
+            config_path.as_os_str(),
+            "--proxy".as_ref(),
+            "http://flag:8080".as_ref(),
+            "info".as_ref(),
+            "jfKfPfyJRdk".as_ref(),
+        ])
+        .unwrap();
+
+        // Flag > environment > file > default
+        let config = load_config(&cli, vars).await.unwrap();
+        assert_eq!(config.http.proxy.as_deref(), Some("http://flag:8080"));
+        assert_eq!(config.http.retries, 9);
+        assert_eq!(config.http.cookies, Some(PathBuf::from("file.txt")));
+        assert_eq!(config.worker.playlist, "index.m3u8");
+        tokio::fs::remove_file(&config_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_mux_keeps_fragments() {
+        // Without ffmpeg, spawning it fails before the exit status is checked
+        let ffmpeg = tokio::process::Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .await;
+        if !ffmpeg.is_ok_and(|o| o.status.success()) {
+            eprintln!("Skipping failed_mux_keeps_fragments: ffmpeg is not installed");
+            return;
+        }
+
+        let workdir = std::env::temp_dir().join(format!("yta-rs-mux-{}", std::process::id()));
+        tokio::fs::create_dir_all(&workdir).await.unwrap();
+        let playlist = workdir.join("index.m3u8");
+        let fragment = workdir.join("seq_0.v299.mp4");
+        tokio::fs::write(&playlist, "not a playlist").await.unwrap();
+        tokio::fs::write(&fragment, b"").await.unwrap();
+
+        let meta = ffmpeg::Metadata {
+            title: None,
+            original_title: None,
+            description: None,
+            thumbnail: None,
+            date: None,
+            video_id: None,
+            subtitles: None,
+            faststart: false,
+        };
+        let res = mux_and_clean(
+            &[&playlist],
+            &meta,
+            &workdir.join("out.mp4"),
+            &workdir,
+            &worker::WorkerConfig::default(),
+            true,
+        )
+        .await;
+
+        // ffmpeg runs, but exits with an error on the invalid playlist
+        assert!(matches!(
+            res,
+            Err(RunError::MuxError(ffmpeg::FfmpegError::ExitStatus(_)))
+        ));
+        assert!(playlist.exists());
+        assert!(fragment.exists());
+        tokio::fs::remove_dir_all(&workdir).await.unwrap();
     }
 }
@@ -0,0 +1,304 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use prometheus::{
+    Encoder, GaugeVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    event::{Event, EventKind},
+    stats::StatisticsSnapshot,
+    util::StatusCounts,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum MetricsError {
+    #[error("Could not create metric: {0}")]
+    PrometheusError(#[from] prometheus::Error),
+    #[error("Could not serve metrics: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+/// Prometheus metrics of all downloads, fed by worker events.
+pub struct Metrics {
+    registry: Registry,
+    segments_downloaded: IntCounter,
+    segments_failed: IntCounter,
+    bytes_downloaded: IntCounterVec,
+    url_refreshes: IntCounter,
+    http_responses: IntCounterVec,
+    active_streams: IntGauge,
+    behind_live: GaugeVec,
+    statuses: Option<Arc<StatusCounts>>,
+    /// Last snapshot of each active stream, to count what changed since
+    snapshots: Mutex<HashMap<String, StatisticsSnapshot>>,
+}
+
+impl Metrics {
+    /// Creates the metrics. HTTP status codes are read from `statuses`, if
+    /// given.
+    pub fn new(statuses: Option<Arc<StatusCounts>>) -> Result<Self, MetricsError> {
+        let registry = Registry::new_custom(Some("yta".to_string()), None)?;
+
+        let segments_downloaded =
+            IntCounter::new("segments_downloaded_total", "Segments downloaded")?;
+        let segments_failed = IntCounter::new(
+            "segments_failed_total",
+            "Segments that could not be downloaded",
+        )?;
+        let bytes_downloaded = IntCounterVec::new(
+            Opts::new("downloaded_bytes_total", "Bytes downloaded per track"),
+            &["itag", "kind"],
+        )?;
+        let url_refreshes = IntCounter::new(
+            "url_refreshes_total",
+            "Times the segment URLs were refreshed",
+        )?;
+        let http_responses = IntCounterVec::new(
+            Opts::new("http_responses_total", "HTTP responses per status code"),
+            &["status"],
+        )?;
+        let active_streams = IntGauge::new("active_streams", "Streams being downloaded")?;
+        let behind_live = GaugeVec::new(
+            Opts::new(
+                "behind_live_seconds",
+                "How far each download is behind the live edge",
+            ),
+            &["video_id"],
+        )?;
+
+        registry.register(Box::new(segments_downloaded.clone()))?;
+        registry.register(Box::new(segments_failed.clone()))?;
+        registry.register(Box::new(bytes_downloaded.clone()))?;
+        registry.register(Box::new(url_refreshes.clone()))?;
+        registry.register(Box::new(http_responses.clone()))?;
+        registry.register(Box::new(active_streams.clone()))?;
+        registry.register(Box::new(behind_live.clone()))?;
+
+        Ok(Self {
+            registry,
+            segments_downloaded,
+            segments_failed,
+            bytes_downloaded,
+            url_refreshes,
+            http_responses,
+            active_streams,
+            behind_live,
+            statuses,
+            snapshots: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn record(&self, event: &Event) {
+        let mut snapshots = self.snapshots.lock().expect("Snapshots poisoned");
+        match &event.kind {
+            EventKind::Progress(snapshot) => {
+                let last = snapshots.get(&event.video_id);
+                let last_downloaded = last.map_or(0, |s| s.segments_downloaded);
+                let last_failed = last.map_or(0, |s| s.segments_failed);
+                // Saturating, in case a stream is downloaded again without
+                // its last run having ended
+                self.segments_downloaded
+                    .inc_by(snapshot.segments_downloaded.saturating_sub(last_downloaded));
+                self.segments_failed
+                    .inc_by(snapshot.segments_failed.saturating_sub(last_failed));
+
+                for (i, track) in snapshot.tracks.iter().enumerate() {
+                    let last_bytes = last
+                        .and_then(|s| s.tracks.get(i))
+                        .map_or(0, |t| t.track.bytes);
+                    self.bytes_downloaded
+                        .with_label_values(&[
+                            &track.track.id.to_string(),
+                            track.track.kind.as_str(),
+                        ])
+                        .inc_by(track.track.bytes.saturating_sub(last_bytes));
+                }
+
+                if let Some(behind) = snapshot.behind_live {
+                    self.behind_live
+                        .with_label_values(&[&event.video_id])
+                        .set(behind);
+                }
+                snapshots.insert(event.video_id.clone(), snapshot.clone());
+            }
+            // Sent once per track, but a stream only counts once
+            EventKind::FormatSelected(_) if !snapshots.contains_key(&event.video_id) => {
+                snapshots.insert(event.video_id.clone(), StatisticsSnapshot::default());
+                self.active_streams.inc();
+            }
+            EventKind::UrlRefreshed => self.url_refreshes.inc(),
+            EventKind::Finished { .. } | EventKind::Failed { .. } => {
+                if snapshots.remove(&event.video_id).is_some() {
+                    self.active_streams.dec();
+                }
+                let _ = self.behind_live.remove_label_values(&[&event.video_id]);
+            }
+            _ => (),
+        }
+    }
+
+    /// Records events until every sender of `events` is dropped.
+    pub async fn listen(&self, mut events: Receiver<Event>) {
+        loop {
+            match events.recv().await {
+                Ok(event) => self.record(&event),
+                Err(RecvError::Lagged(n)) => warn!("Metrics missed {} events", n),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        if let Some(statuses) = &self.statuses {
+            for (status, count) in statuses.get() {
+                let counter = self
+                    .http_responses
+                    .with_label_values(&[&status.to_string()]);
+                counter.inc_by(count.saturating_sub(counter.get()));
+            }
+        }
+
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("Could not encode metrics");
+        String::from_utf8(buf).expect("Metrics are not valid UTF-8")
+    }
+}
+
+async fn metrics_handler(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics.render(),
+    )
+}
+
+/// Serves the metrics on `/metrics` until `cancel` is triggered.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    metrics: Arc<Metrics>,
+    cancel: CancellationToken,
+) -> Result<(), MetricsError> {
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(metrics);
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    axum::serve(listener, app)
+        .with_graceful_shutdown(cancel.cancelled_owned())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        source::{Track, TrackKind},
+        stats::DownloadStatistics,
+    };
+
+    fn event(kind: EventKind) -> Event {
+        Event {
+            video_id: "abc".to_string(),
+            time: chrono::Utc::now(),
+            kind,
+        }
+    }
+
+    #[test]
+    fn record_events() {
+        let track = Track {
+            id: 140,
+            kind: TrackKind::Audio,
+            codecs: "mp4a.40.2".to_string(),
+            bandwidth: 128000,
+            width: None,
+            height: None,
+            frame_rate: None,
+        };
+        let metrics = Metrics::new(None).unwrap();
+        let mut stats =
+            DownloadStatistics::new(std::slice::from_ref(&track), Duration::from_secs(2));
+        stats.segments_total = 5;
+
+        metrics.record(&event(EventKind::FormatSelected(track)));
+        for seq in 0..3 {
+            stats.add_segment(seq, &[1000]);
+            metrics.record(&event(EventKind::Progress(stats.snapshot())));
+        }
+        stats.add_failure();
+        metrics.record(&event(EventKind::Progress(stats.snapshot())));
+        metrics.record(&event(EventKind::UrlRefreshed));
+
+        let text = metrics.render();
+        assert!(text.contains("yta_segments_downloaded_total 3\n"));
+        assert!(text.contains("yta_segments_failed_total 1\n"));
+        assert!(text.contains("yta_downloaded_bytes_total{itag=\"140\",kind=\"audio\"} 3000\n"));
+        assert!(text.contains("yta_url_refreshes_total 1\n"));
+        assert!(text.contains("yta_active_streams 1\n"));
+        assert!(text.contains("yta_behind_live_seconds{video_id=\"abc\"} 4\n"));
+
+        metrics.record(&event(EventKind::Finished {
+            segments_downloaded: 3,
+            bytes_downloaded: 3000,
+        }));
+        let text = metrics.render();
+        assert!(text.contains("yta_active_streams 0\n"));
+        assert!(!text.contains("yta_behind_live_seconds{"));
+    }
+
+    #[test]
+    fn rearchive_after_error() {
+        let track = Track {
+            id: 140,
+            kind: TrackKind::Audio,
+            codecs: "mp4a.40.2".to_string(),
+            bandwidth: 128000,
+            width: None,
+            height: None,
+            frame_rate: None,
+        };
+        let metrics = Metrics::new(None).unwrap();
+        let run = |segments: i64| {
+            let mut stats =
+                DownloadStatistics::new(std::slice::from_ref(&track), Duration::from_secs(2));
+            metrics.record(&event(EventKind::FormatSelected(track.clone())));
+            for seq in 0..segments {
+                stats.add_segment(seq, &[1000]);
+                metrics.record(&event(EventKind::Progress(stats.snapshot())));
+            }
+        };
+
+        run(3);
+        metrics.record(&event(EventKind::Failed {
+            error: "Worker error".to_string(),
+        }));
+        let text = metrics.render();
+        assert!(text.contains("yta_active_streams 0\n"));
+
+        // The monitor picks the stream up again, with counters from zero
+        run(2);
+        let text = metrics.render();
+        assert!(text.contains("yta_segments_downloaded_total 5\n"));
+        assert!(text.contains("yta_downloaded_bytes_total{itag=\"140\",kind=\"audio\"} 5000\n"));
+        assert!(text.contains("yta_active_streams 1\n"));
+
+        // A run that ended without an event does not wrap the counters
+        run(1);
+        let text = metrics.render();
+        assert!(text.contains("yta_segments_downloaded_total 5\n"));
+        assert!(text.contains("yta_active_streams 1\n"));
+    }
+}
@@ -0,0 +1,431 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    select,
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    channel::{self, Channel},
+    event::EventSender,
+    feed, ffmpeg,
+    info::LiveStatus,
+    player_response::{InitialPlayerResponse, PlayerResponseError, Status},
+    subtitle::{self, SubtitleFormat},
+    template::{self, Template},
+    util, worker,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum MonitorError {
+    #[error("I/O error")]
+    IoError(#[from] std::io::Error),
+    #[error("Could not parse list of archived videos")]
+    ParseError(#[from] serde_json::Error),
+    #[error("Error getting initial player response")]
+    InitialPlayerResponseError(#[from] PlayerResponseError),
+    #[error("Worker error")]
+    WorkerError(#[from] worker::WorkerError),
+    #[error("Mux error")]
+    MuxError(#[from] ffmpeg::FfmpegError),
+    #[error("Could not check channel page")]
+    ChannelError(#[from] channel::ChannelError),
+    #[error("Could not check channel feed")]
+    FeedError(#[from] feed::FeedError),
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonitorConfig {
+    pub channels: Vec<Channel>,
+    /// How often each channel is checked for a new stream, in seconds
+    #[serde(deserialize_with = "util::deserialize_secs")]
+    pub poll_interval: Duration,
+    /// Maximum number of streams downloaded at the same time
+    pub max_concurrent: usize,
+    /// Directory that the templates below are relative to
+    pub outdir: PathBuf,
+    /// Working directory of each stream. In a config file, this is shared
+    /// with other commands and set outside the monitor section.
+    #[serde(skip)]
+    pub workdir: Template,
+    /// File each stream is muxed to
+    #[serde(skip)]
+    pub output: Template,
+    #[serde(skip)]
+    pub worker: worker::WorkerConfig,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            channels: Vec::new(),
+            poll_interval: Duration::from_secs(60),
+            max_concurrent: 4,
+            outdir: PathBuf::from("."),
+            workdir: Template::new(template::DEFAULT_WORKDIR).expect("Invalid default template"),
+            output: Template::new(template::DEFAULT_OUTPUT).expect("Invalid default template"),
+            worker: worker::WorkerConfig::default(),
+        }
+    }
+}
+
+/// Video IDs that have already been archived, persisted to disk so streams
+/// are not downloaded twice across restarts
+struct Archive {
+    path: PathBuf,
+    done: BTreeSet<String>,
+    /// Streams being waited on or downloaded by this process
+    active: BTreeSet<String>,
+}
+
+impl Archive {
+    async fn load(path: PathBuf) -> Result<Self, MonitorError> {
+        let done = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path,
+            done,
+            active: BTreeSet::new(),
+        })
+    }
+
+    fn is_known(&self, video_id: &str) -> bool {
+        self.done.contains(video_id) || self.active.contains(video_id)
+    }
+
+    /// Marks a stream as active, returning false if it was already seen.
+    fn start(&mut self, video_id: &str) -> bool {
+        !self.done.contains(video_id) && self.active.insert(video_id.to_string())
+    }
+
+    async fn finish(&mut self, video_id: &str) -> Result<(), MonitorError> {
+        self.active.remove(video_id);
+        self.done.insert(video_id.to_string());
+
+        let temp_path = self.path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, serde_json::to_vec_pretty(&self.done)?).await?;
+        tokio::fs::rename(&temp_path, &self.path).await?;
+
+        Ok(())
+    }
+}
+
+/// Whether a stream that is not usable yet may still become usable. Streams
+/// that are not playable for another reason than not having started, such
+/// as members-only or age-restricted ones, never will be.
+fn may_start(ipr: &InitialPlayerResponse) -> bool {
+    let is_upcoming = ipr
+        .video_details
+        .as_ref()
+        .map(|v| v.is_upcoming || v.is_live)
+        .unwrap_or(false);
+    let status = &ipr.playability_status.status;
+    is_upcoming && matches!(status, Status::Ok | Status::LiveStreamOffline)
+}
+
+/// Polls the player response of an upcoming stream until it goes live.
+/// Returns `None` if the stream is no longer live or upcoming, e.g. if it
+/// was cancelled, or if it cannot be played, e.g. if it is members-only.
+pub async fn wait_for_live(
+    client: &util::HttpClient,
+    video_id: &str,
+    interval: Duration,
+) -> Result<Option<InitialPlayerResponse>, PlayerResponseError> {
+    loop {
+        let ipr = InitialPlayerResponse::fetch(client, video_id).await?;
+        if ipr.is_usable() {
+            return Ok(Some(ipr));
+        }
+        if !may_start(&ipr) {
+            if let Some(reason) = &ipr.playability_status.reason {
+                warn!("[{}] Cannot be played: {}", video_id, reason);
+            }
+            return Ok(None);
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Downloads and muxes a stream, waiting for it to start if it is upcoming.
+/// Returns whether the stream is done with, which is not the case if it was
+/// cancelled before it ended.
+async fn archive(
+    client: &util::HttpClient,
+    ipr: InitialPlayerResponse,
+    video_id: &str,
+    config: &MonitorConfig,
+    semaphore: &Semaphore,
+    cancel: CancellationToken,
+    events: Option<&EventSender>,
+) -> Result<bool, MonitorError> {
+    let poll_interval = config.poll_interval;
+    let ipr = if ipr.is_usable() {
+        ipr
+    } else {
+        info!("[{}] Waiting for stream to start", video_id);
+        let ipr = select! {
+            ipr = wait_for_live(client, video_id, poll_interval) => ipr?,
+            _ = cancel.cancelled() => return Ok(false),
+        };
+        match ipr {
+            Some(ipr) => ipr,
+            None => {
+                warn!(
+                    "[{}] Stream is no longer upcoming, or cannot be played",
+                    video_id
+                );
+                return Ok(true);
+            }
+        }
+    };
+
+    let _permit = select! {
+        permit = semaphore.acquire() => permit.expect("Semaphore closed"),
+        _ = cancel.cancelled() => return Ok(false),
+    };
+    info!("[{}] Starting download", video_id);
+    let workdir = config.outdir.join(config.workdir.render_ipr(&ipr));
+    tokio::fs::create_dir_all(&workdir).await?;
+    let info = worker::start(
+        client,
+        &ipr,
+        &workdir,
+        &config.worker,
+        cancel.clone(),
+        events,
+    )
+    .await?;
+
+    let thumbnail = Some(workdir.join("thumbnail.jpg")).filter(|p| p.exists());
+    let mut meta = ffmpeg::Metadata::from_info(&info, thumbnail);
+    meta.subtitles = subtitle::chat_track(
+        None,
+        &workdir,
+        &config.worker.playlist,
+        &info,
+        SubtitleFormat::default(),
+    )
+    .await;
+    let output = config.outdir.join(config.output.render(&info));
+    if let Some(parent) = output.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    ffmpeg::mux(&workdir.join(&config.worker.playlist), &meta, &output).await?;
+
+    // A stream that is still live is picked up again after a restart. The
+    // worker resumes the playlists in the same working directory, so the
+    // output is muxed again with the segments from before and after
+    if cancel.is_cancelled() {
+        info!("[{}] Stopped before the stream ended", video_id);
+        return Ok(false);
+    }
+    info!("[{}] Finished", video_id);
+
+    Ok(true)
+}
+
+/// Returns the ID of a channel. Handles are looked up once and kept in
+/// `ids`, so that only the feed is fetched afterwards.
+async fn channel_id(
+    client: &util::HttpClient,
+    channel: &Channel,
+    ids: &mut HashMap<Channel, String>,
+) -> Result<String, MonitorError> {
+    if let Some(id) = ids.get(channel) {
+        return Ok(id.clone());
+    }
+    let id = channel::resolve_id(client, channel).await?;
+    if matches!(channel, Channel::Handle(_)) {
+        info!("{} has the ID {}", channel, id);
+    }
+    ids.insert(channel.clone(), id.clone());
+    Ok(id)
+}
+
+/// Finds live and upcoming streams of a channel through its feed, which is
+/// lighter than the channel page and also lists streams scheduled further
+/// ahead. Videos that turn out not to be streams are added to `checked` so
+/// they are only fetched once.
+async fn find_streams(
+    client: &util::HttpClient,
+    channel_id: &str,
+    archive: &Mutex<Archive>,
+    checked: &mut BTreeSet<String>,
+) -> Result<Vec<(String, InitialPlayerResponse)>, MonitorError> {
+    let mut streams = Vec::new();
+    for entry in feed::fetch_feed(client, channel_id).await?.entries {
+        if checked.contains(&entry.video_id) || archive.lock().await.is_known(&entry.video_id) {
+            continue;
+        }
+
+        match feed::classify(client, &entry.video_id).await {
+            Ok((LiveStatus::IsLive | LiveStatus::IsUpcoming, ipr)) => {
+                streams.push((entry.video_id, ipr));
+            }
+            Ok(_) => {
+                checked.insert(entry.video_id);
+            }
+            Err(e) => warn!("Could not check {}: {}", entry.video_id, e),
+        }
+    }
+
+    Ok(streams)
+}
+
+/// Watches channels for live and upcoming streams and archives each one.
+/// Runs until an unrecoverable error occurs, or until `cancel` is triggered,
+/// after which the streams being downloaded are finished up and muxed.
+/// Events of every download are published to `events`, if given.
+pub async fn run(
+    client: Arc<util::HttpClient>,
+    config: MonitorConfig,
+    cancel: CancellationToken,
+    events: Option<EventSender>,
+) -> Result<(), MonitorError> {
+    let config = Arc::new(config);
+    tokio::fs::create_dir_all(&config.outdir).await?;
+    let archive_state = Arc::new(Mutex::new(
+        Archive::load(config.outdir.join("archived.json")).await?,
+    ));
+    let semaphore = Arc::new(Semaphore::new(config.max_concurrent.max(1)));
+
+    let mut checked = BTreeSet::new();
+    let mut channel_ids = HashMap::new();
+    let mut tasks = JoinSet::new();
+
+    info!("Monitoring {} channels", config.channels.len());
+    while !cancel.is_cancelled() {
+        let mut streams = Vec::new();
+        for channel in &config.channels {
+            let find = async {
+                let id = channel_id(&client, channel, &mut channel_ids).await?;
+                find_streams(&client, &id, &archive_state, &mut checked).await
+            };
+            let found = select! {
+                found = find => found,
+                _ = cancel.cancelled() => break,
+            };
+            match found {
+                Ok(found) if found.is_empty() => debug!("{} is not live", channel),
+                Ok(found) => streams.extend(found.into_iter().map(|s| (channel, s))),
+                Err(e) => warn!("Could not check {}: {}", channel, e),
+            }
+        }
+
+        for (channel, (video_id, ipr)) in streams {
+            if cancel.is_cancelled() || !archive_state.lock().await.start(&video_id) {
+                continue;
+            }
+            info!("Found stream {} on {}", video_id, channel);
+
+            let client = client.clone();
+            let archive_state = archive_state.clone();
+            let semaphore = semaphore.clone();
+            let config = config.clone();
+            let cancel = cancel.clone();
+            let events = events.clone();
+            tasks.spawn(async move {
+                let res = archive(
+                    &client,
+                    ipr,
+                    &video_id,
+                    &config,
+                    &semaphore,
+                    cancel,
+                    events.as_ref(),
+                )
+                .await;
+
+                let mut archive_state = archive_state.lock().await;
+                match res {
+                    Ok(true) => {
+                        if let Err(e) = archive_state.finish(&video_id).await {
+                            error!("[{}] Could not save archive list: {}", video_id, e);
+                        }
+                    }
+                    Ok(false) => {
+                        archive_state.active.remove(&video_id);
+                    }
+                    Err(e) => {
+                        // Allow the stream to be picked up again
+                        error!("[{}] Download failed: {}", video_id, e);
+                        archive_state.active.remove(&video_id);
+                    }
+                }
+            });
+        }
+
+        // Reap finished downloads
+        while tasks.try_join_next().is_some() {}
+
+        select! {
+            _ = tokio::time::sleep(config.poll_interval) => (),
+            _ = cancel.cancelled() => (),
+        }
+    }
+
+    info!("Stopping, waiting for {} downloads to finish", tasks.len());
+    while tasks.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_ipr(fname: &str) -> InitialPlayerResponse {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources/test")
+            .join(fname);
+        let html = std::fs::read_to_string(path).unwrap();
+        InitialPlayerResponse::from_html(&html).unwrap()
+    }
+
+    #[test]
+    fn may_start() {
+        let ipr = get_test_ipr("watchpage_scheduled.html");
+        assert!(!ipr.is_usable());
+        assert!(super::may_start(&ipr));
+
+        // Members-only and age-restricted streams are never usable
+        for status in [Status::LoginRequired, Status::Unplayable, Status::Error] {
+            let mut ipr = ipr.clone();
+            ipr.playability_status.status = status;
+            assert!(!super::may_start(&ipr));
+        }
+
+        assert!(!super::may_start(&get_test_ipr("watchpage_post_live.html")));
+    }
+
+    #[tokio::test]
+    async fn archive_persists() {
+        let dir = std::env::temp_dir().join(format!("yta-rs-monitor-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("archived.json");
+
+        let mut archive = Archive::load(path.clone()).await.unwrap();
+        assert!(archive.start("jfKfPfyJRdk"));
+        assert!(!archive.start("jfKfPfyJRdk"));
+        archive.finish("jfKfPfyJRdk").await.unwrap();
+
+        let mut archive = Archive::load(path).await.unwrap();
+        assert!(!archive.start("jfKfPfyJRdk"));
+        assert!(archive.start("Fl1vM3scybw"));
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,14 +1,14 @@
 use std::collections::HashMap;
 
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_aux::prelude::*;
 
-use crate::{dash, util};
+use crate::{cipher, dash, hls, storyboard, util};
 
 // Generated with https://transform.tools/json-to-rust-serde
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct InitialPlayerResponse {
     pub response_context: ResponseContext,
@@ -16,21 +16,25 @@ pub struct InitialPlayerResponse {
     pub streaming_data: Option<StreamingData>,
     pub video_details: Option<VideoDetails>,
     pub microformat: Option<Microformat>,
+    pub storyboards: Option<Storyboards>,
+    /// URL of the player JS, taken from the watch page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub player_url: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ResponseContext {
     pub main_app_web_response_context: MainAppWebResponseContext,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MainAppWebResponseContext {
     pub logged_out: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayabilityStatus {
     pub status: Status,
@@ -38,7 +42,7 @@ pub struct PlayabilityStatus {
     pub live_streamability: Option<LiveStreamability>,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Status {
     Ok,
@@ -48,13 +52,13 @@ pub enum Status {
     Error,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LiveStreamability {
     pub live_streamability_renderer: LiveStreamabilityRenderer,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LiveStreamabilityRenderer {
     pub video_id: String,
@@ -63,39 +67,135 @@ pub struct LiveStreamabilityRenderer {
     pub offline_slate: Option<OfflineSlate>,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OfflineSlate {
     pub live_stream_offline_slate_renderer: LiveStreamOfflineSlateRenderer,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LiveStreamOfflineSlateRenderer {
-    #[serde(deserialize_with = "deserialize_datetime_utc_from_seconds")]
+    #[serde(
+        deserialize_with = "deserialize_datetime_utc_from_seconds",
+        serialize_with = "chrono::serde::ts_seconds::serialize"
+    )]
     pub scheduled_start_time: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StreamingData {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub expires_in_seconds: i64,
     pub adaptive_formats: Vec<AdaptiveFormat>,
     pub dash_manifest_url: Option<String>,
+    pub hls_manifest_url: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AdaptiveFormat {
     pub itag: i64,
     pub url: Option<String>,
+    /// Scrambled URL, used instead of `url` by some videos
+    pub signature_cipher: Option<String>,
     pub mime_type: String,
     pub bitrate: i64,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub fps: Option<f64>,
+    pub quality_label: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub audio_sample_rate: Option<u32>,
+    pub audio_channels: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub content_length: Option<u64>,
+    pub projection_type: Option<String>,
+    pub color_info: Option<ColorInfo>,
     pub target_duration_sec: Option<f64>,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+impl AdaptiveFormat {
+    pub fn mime(&self) -> Option<MimeType> {
+        MimeType::parse(&self.mime_type)
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.mime_type.starts_with("audio/")
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.mime_type.starts_with("video/")
+    }
+
+    pub fn is_hdr(&self) -> bool {
+        self.color_info.as_ref().is_some_and(|c| c.is_hdr())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ColorInfo {
+    pub primaries: Option<String>,
+    pub transfer_characteristics: Option<String>,
+    pub matrix_coefficients: Option<String>,
+}
+
+impl ColorInfo {
+    /// Whether the transfer function is PQ or HLG
+    pub fn is_hdr(&self) -> bool {
+        matches!(
+            self.transfer_characteristics.as_deref(),
+            Some("COLOR_TRANSFER_CHARACTERISTICS_SMPTEST2084")
+                | Some("COLOR_TRANSFER_CHARACTERISTICS_ARIB_STD_B67")
+        )
+    }
+}
+
+/// A parsed mime type, e.g. `video/mp4; codecs="avc1.64001f"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MimeType {
+    /// `audio` or `video`
+    pub kind: String,
+    /// e.g. `mp4` or `webm`
+    pub container: String,
+    pub codecs: Vec<String>,
+}
+
+impl MimeType {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split(';');
+        let (kind, container) = parts.next()?.trim().split_once('/')?;
+        let codecs = parts
+            .filter_map(|p| p.trim().strip_prefix("codecs="))
+            .flat_map(|c| c.trim_matches('"').split(','))
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .collect();
+
+        Some(Self {
+            kind: kind.to_string(),
+            container: container.to_string(),
+            codecs,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Storyboards {
+    pub player_storyboard_spec_renderer: Option<StoryboardSpecRenderer>,
+    pub player_live_storyboard_spec_renderer: Option<StoryboardSpecRenderer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoryboardSpecRenderer {
+    pub spec: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VideoDetails {
     pub video_id: String,
@@ -104,6 +204,8 @@ pub struct VideoDetails {
     pub length_seconds: i64,
     #[serde(default)]
     pub is_live: bool,
+    #[serde(default)]
+    pub is_upcoming: bool,
     pub channel_id: String,
     pub is_owner_viewing: bool,
     pub short_description: String,
@@ -112,15 +214,18 @@ pub struct VideoDetails {
     pub view_count: i64,
     pub author: String,
     pub is_live_content: bool,
+    /// Set while a stream that just ended can still be seeked like a live one
+    #[serde(default)]
+    pub is_post_live_dvr: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Microformat {
     pub player_microformat_renderer: PlayerMicroformatRenderer,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayerMicroformatRenderer {
     pub thumbnail: Thumbnail,
@@ -130,29 +235,34 @@ pub struct PlayerMicroformatRenderer {
     pub upload_date: String,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Thumbnail {
     pub thumbnails: Vec<ThumbnailURL>,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ThumbnailURL {
     pub url: String,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LiveBroadcastDetails {
     pub is_live_now: bool,
     pub start_timestamp: String,
+    pub end_timestamp: Option<String>,
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum PlayerResponseError {
     #[error("Could not find initial player response")]
     NoInitialPlayerResponse,
+    #[error("Could not fetch watch page")]
+    FetchWatchPageError(util::DownloadError),
     #[error("Could not parse initial player response")]
     ParseInitialPlayerResponse(#[from] serde_json::Error),
     #[error("No DASH manifest URL found")]
@@ -161,6 +271,16 @@ pub enum PlayerResponseError {
     DownloadDashManifestError(#[from] util::DownloadError),
     #[error("Could not parse DASH manifest")]
     ParseDashManifestError(#[from] quick_xml::Error),
+    #[error("No HLS manifest URL found")]
+    NoHlsManifestURL,
+    #[error("Could not download HLS manifest")]
+    DownloadHlsManifestError(util::DownloadError),
+    #[error("Could not parse HLS manifest")]
+    ParseHlsManifestError(#[from] hls::ParseError),
+    #[error("No player URL found")]
+    NoPlayerURL,
+    #[error("Could not decipher format URLs")]
+    CipherError(#[from] cipher::CipherError),
 }
 
 const IPR_STR: &str = "var ytInitialPlayerResponse =";
@@ -187,20 +307,40 @@ impl InitialPlayerResponse {
         let ipr_str = get_ipr_str(html).ok_or(PlayerResponseError::NoInitialPlayerResponse)?;
 
         // Parse the JSON
-        serde_json::from_str(ipr_str).map_err(PlayerResponseError::ParseInitialPlayerResponse)
+        let mut ipr: Self = serde_json::from_str(ipr_str)
+            .map_err(PlayerResponseError::ParseInitialPlayerResponse)?;
+        ipr.player_url = cipher::player_url(html);
+
+        Ok(ipr)
+    }
+
+    /// Fetches and parses the watch page of a video.
+    pub async fn fetch(
+        client: &util::HttpClient,
+        video_id: &str,
+    ) -> Result<Self, PlayerResponseError> {
+        let url = format!("https://www.youtube.com/watch?v={}", video_id);
+        let html = client
+            .fetch_text(&url)
+            .await
+            .map_err(PlayerResponseError::FetchWatchPageError)?;
+        Self::from_html(&html)
+    }
+
+    pub fn video_id(&self) -> Option<&str> {
+        self.video_details.as_ref().map(|v| v.video_id.as_str())
     }
 
     pub fn is_usable(&self) -> bool {
         self.video_details
             .as_ref()
-            .map(|v| v.video_id.clone())
-            .unwrap_or("".into())
-            != ""
+            .map(|v| !v.video_id.is_empty())
+            .unwrap_or(false)
             && self
                 .playability_status
                 .live_streamability
                 .as_ref()
-                .map(|ls| ls.live_streamability_renderer.video_id != "")
+                .map(|ls| !ls.live_streamability_renderer.video_id.is_empty())
                 .unwrap_or(false)
             && self.playability_status.status == Status::Ok
             && self
@@ -216,6 +356,26 @@ impl InitialPlayerResponse {
                 .unwrap_or(false)
     }
 
+    /// Whether the video is a finished VOD that can be downloaded directly
+    /// from its adaptive formats, such as a processed past live stream.
+    pub fn is_vod(&self) -> bool {
+        self.playability_status.status == Status::Ok
+            && self
+                .video_details
+                .as_ref()
+                .map(|v| !v.is_live && !v.is_upcoming)
+                .unwrap_or(false)
+            && self
+                .streaming_data
+                .as_ref()
+                .map(|sd| {
+                    sd.adaptive_formats
+                        .iter()
+                        .any(|f| f.url.is_some() || f.signature_cipher.is_some())
+                })
+                .unwrap_or(false)
+    }
+
     pub fn target_duration(&self) -> Option<f64> {
         self.streaming_data
             .as_ref()?
@@ -224,20 +384,57 @@ impl InitialPlayerResponse {
             .target_duration_sec
     }
 
+    /// Parses the storyboard spec, preferring the live storyboard if the
+    /// video has one.
+    pub fn storyboard(&self) -> Option<storyboard::Storyboard> {
+        let storyboards = self.storyboards.as_ref()?;
+        match (
+            &storyboards.player_live_storyboard_spec_renderer,
+            &storyboards.player_storyboard_spec_renderer,
+        ) {
+            (Some(live), _) => storyboard::parse_spec(&live.spec, true),
+            (None, Some(vod)) => storyboard::parse_spec(&vod.spec, false),
+            (None, None) => None,
+        }
+    }
+
+    /// Returns the URL of each adaptive format by itag. Only formats with a
+    /// plain `url` are included, so formats that only have a
+    /// `signatureCipher` are missing unless [`Self::decipher_formats`] was
+    /// called first.
     pub fn get_adaptive_formats(&self) -> Option<HashMap<i64, String>> {
         Some(
             self.streaming_data
                 .as_ref()?
                 .adaptive_formats
                 .iter()
-                .filter_map(|af| match af.url {
-                    Some(ref url) => Some((af.itag, url.clone())),
-                    None => None,
-                })
+                .filter_map(|af| af.url.as_ref().map(|url| (af.itag, url.clone())))
                 .collect(),
         )
     }
 
+    /// Makes the URLs of adaptive formats usable by deciphering signatures
+    /// and transforming the n parameter. The player JS is cached in
+    /// `cache_dir`.
+    pub async fn decipher_formats(
+        &mut self,
+        client: &util::HttpClient,
+        cache_dir: &std::path::Path,
+    ) -> Result<(), PlayerResponseError> {
+        let player_url = self
+            .player_url
+            .as_ref()
+            .ok_or(PlayerResponseError::NoPlayerURL)?;
+        let player = cipher::Player::fetch(client, player_url, cache_dir).await?;
+
+        if let Some(sd) = self.streaming_data.as_mut() {
+            let count = player.decipher_formats(&mut sd.adaptive_formats)?;
+            debug!("Deciphered {} format URLs", count);
+        }
+
+        Ok(())
+    }
+
     pub async fn get_dash_representations(
         &self,
         client: &util::HttpClient,
@@ -256,6 +453,26 @@ impl InitialPlayerResponse {
                 dash::parse_manifest(&manifest).map_err(PlayerResponseError::ParseDashManifestError)
             })
     }
+
+    pub async fn get_hls_master_playlist(
+        &self,
+        client: &util::HttpClient,
+    ) -> Result<hls::MasterPlaylist, PlayerResponseError> {
+        let hls_url = self
+            .streaming_data
+            .as_ref()
+            .and_then(|sd| sd.hls_manifest_url.as_ref())
+            .ok_or(PlayerResponseError::NoHlsManifestURL)?;
+
+        client
+            .fetch_text(hls_url)
+            .await
+            .map_err(PlayerResponseError::DownloadHlsManifestError)
+            .and_then(|manifest| {
+                hls::parse_master_playlist(&manifest, hls_url)
+                    .map_err(PlayerResponseError::ParseHlsManifestError)
+            })
+    }
 }
 
 #[cfg(test)]
@@ -284,7 +501,7 @@ mod tests {
         let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         d.push("resources/test/");
         d.push(fname);
-        std::fs::read_to_string(d).expect(format!("Could not read {}", fname).as_str())
+        std::fs::read_to_string(d).unwrap_or_else(|_| panic!("Could not read {}", fname))
     }
 
     #[test]
@@ -293,7 +510,7 @@ mod tests {
         let ipr = InitialPlayerResponse::from_html(&html).expect("Could not parse IPR");
         let details = ipr.video_details.unwrap();
 
-        assert_eq!(details.is_live, true, "Video is not live");
+        assert!(details.is_live, "Video is not live");
         assert_eq!(details.length_seconds, 0, "Video length is not 0");
         assert_eq!(details.view_count, 210_943_922, "View count is not correct");
         assert!(
@@ -307,13 +524,99 @@ mod tests {
         );
     }
 
+    #[test]
+    fn ipr_vod() {
+        let html = get_test_html("watchpage_post_live.html");
+        let ipr = InitialPlayerResponse::from_html(&html).expect("Could not parse IPR");
+        assert!(ipr.is_vod());
+        assert!(!ipr.is_usable());
+        assert_eq!(
+            ipr.player_url.as_deref(),
+            Some("https://www.youtube.com/s/player/bbe1b497/player_ias.vflset/en_US/base.js")
+        );
+        assert_eq!(
+            ipr.streaming_data.unwrap().adaptive_formats[0].content_length,
+            Some(482_541_108)
+        );
+
+        let html = get_test_html("watchpage_live.html");
+        let ipr = InitialPlayerResponse::from_html(&html).expect("Could not parse IPR");
+        assert!(!ipr.is_vod());
+
+        let html = get_test_html("watchpage_scheduled.html");
+        let ipr = InitialPlayerResponse::from_html(&html).expect("Could not parse IPR");
+        assert!(!ipr.is_vod());
+    }
+
+    #[test]
+    fn adaptive_format_fields() {
+        let html = get_test_html("watchpage_post_live.html");
+        let ipr = InitialPlayerResponse::from_html(&html).expect("Could not parse IPR");
+        let formats = ipr.streaming_data.unwrap().adaptive_formats;
+
+        let video = formats.iter().find(|f| f.itag == 136).unwrap();
+        assert!(video.is_video());
+        assert_eq!((video.width, video.height), (Some(1280), Some(720)));
+        assert_eq!(video.fps, Some(30.0));
+        assert_eq!(video.quality_label.as_deref(), Some("720p"));
+        assert_eq!(video.projection_type.as_deref(), Some("RECTANGULAR"));
+        assert!(!video.is_hdr());
+
+        let audio = formats.iter().find(|f| f.itag == 251).unwrap();
+        assert!(audio.is_audio());
+        assert_eq!(audio.audio_sample_rate, Some(48000));
+        assert_eq!(audio.audio_channels, Some(2));
+        assert_eq!(audio.content_length, Some(69_189_552));
+        assert_eq!(
+            audio.mime(),
+            Some(MimeType {
+                kind: "audio".to_string(),
+                container: "webm".to_string(),
+                codecs: vec!["opus".to_string()],
+            })
+        );
+    }
+
+    #[test]
+    fn mime_type() {
+        let mime = MimeType::parse(r#"video/mp4; codecs="avc1.4d401f, mp4a.40.2""#).unwrap();
+        assert_eq!(mime.kind, "video");
+        assert_eq!(mime.container, "mp4");
+        assert_eq!(mime.codecs, vec!["avc1.4d401f", "mp4a.40.2"]);
+
+        let mime = MimeType::parse("audio/mp4").unwrap();
+        assert!(mime.codecs.is_empty());
+
+        assert!(MimeType::parse("invalid").is_none());
+    }
+
+    #[test]
+    fn ipr_storyboard() {
+        let html = get_test_html("watchpage_live.html");
+        let ipr = InitialPlayerResponse::from_html(&html).expect("Could not parse IPR");
+        let sb = ipr.storyboard().expect("No storyboard");
+        assert!(sb.is_live);
+        assert_eq!(sb.levels.len(), 1);
+
+        let html = get_test_html("watchpage_post_live.html");
+        let ipr = InitialPlayerResponse::from_html(&html).expect("Could not parse IPR");
+        let sb = ipr.storyboard().expect("No storyboard");
+        assert!(!sb.is_live);
+        assert_eq!(sb.levels.len(), 3);
+        assert_eq!(sb.levels[2].sheet_count(), Some(16));
+
+        let html = get_test_html("watchpage_scheduled.html");
+        let ipr = InitialPlayerResponse::from_html(&html).expect("Could not parse IPR");
+        assert!(ipr.storyboard().is_none());
+    }
+
     #[test]
     fn ipr_scheduled() {
         let html = get_test_html("watchpage_scheduled.html");
         let ipr = InitialPlayerResponse::from_html(&html).expect("Could not parse IPR");
         let details = ipr.video_details.unwrap();
 
-        assert_eq!(details.is_live, false, "Video is live");
+        assert!(!details.is_live, "Video is live");
         assert_eq!(
             ipr.playability_status.status,
             Status::LiveStreamOffline,
@@ -0,0 +1,378 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tower_http::services::ServeDir;
+
+use crate::info::{Format, InfoJson, LiveStatus};
+
+const PLAYER_HTML: &str = include_str!("../resources/player/index.html");
+const PLAYER_JS: &str = include_str!("../resources/player/player.js");
+
+/// How deep working directories are looked for, e.g. `{channel}/{id}` is 2
+const MAX_DEPTH: usize = 4;
+
+/// How much of a playlist was downloaded
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlaylistProgress {
+    pub segments: usize,
+    /// Total duration in seconds
+    pub duration: f64,
+    /// Whether the playlist was closed, so no more segments are added
+    pub finished: bool,
+}
+
+impl PlaylistProgress {
+    /// Reads the progress from a media playlist.
+    pub fn parse(playlist: &str) -> Self {
+        let mut progress = Self {
+            segments: 0,
+            duration: 0.0,
+            finished: false,
+        };
+        for line in playlist.lines() {
+            if let Some(info) = line.strip_prefix("#EXTINF:") {
+                let duration = info.split(',').next().unwrap_or_default();
+                progress.segments += 1;
+                progress.duration += duration.parse::<f64>().unwrap_or_default();
+            } else if line == "#EXT-X-ENDLIST" {
+                progress.finished = true;
+            }
+        }
+        progress
+    }
+
+    /// Reads the progress from the first media playlist of a master
+    /// playlist.
+    async fn read(master: &Path) -> std::io::Result<Self> {
+        let text = tokio::fs::read_to_string(master).await?;
+        let Some(uri) = text.lines().find(|l| !l.is_empty() && !l.starts_with('#')) else {
+            return Ok(Self::parse(""));
+        };
+        let dir = master.parent().unwrap_or(Path::new(""));
+        Ok(Self::parse(
+            &tokio::fs::read_to_string(dir.join(uri)).await?,
+        ))
+    }
+}
+
+/// A working directory with a metadata sidecar
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Archive {
+    /// Directory relative to the served one, separated by `/`
+    pub path: String,
+    pub id: String,
+    pub title: String,
+    pub channel: String,
+    pub live_status: LiveStatus,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub formats: Vec<Format>,
+    pub gaps: usize,
+    pub thumbnail: bool,
+    /// Master playlist relative to the served directory, if the video was
+    /// downloaded live
+    pub playlist: Option<String>,
+    pub progress: Option<PlaylistProgress>,
+}
+
+impl Archive {
+    async fn read(root: &Path, dir: &Path, playlist: &str) -> std::io::Result<Self> {
+        let info = InfoJson::read(&dir.join("info.json")).await?;
+        let path = dir
+            .strip_prefix(root)
+            .unwrap_or(dir)
+            .iter()
+            .map(|c| c.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let master = dir.join(playlist);
+        let progress = match tokio::fs::try_exists(&master).await? {
+            true => Some(PlaylistProgress::read(&master).await?),
+            false => None,
+        };
+        let playlist = progress.as_ref().map(|_| match path.as_str() {
+            "" => playlist.to_string(),
+            _ => format!("{}/{}", path, playlist),
+        });
+
+        Ok(Self {
+            path,
+            id: info.id,
+            title: info.title,
+            channel: info.channel,
+            live_status: info.live_status,
+            start_time: info.actual_start_time,
+            end_time: info.actual_end_time,
+            formats: info.requested_formats,
+            gaps: info.gaps.len(),
+            thumbnail: tokio::fs::try_exists(dir.join("thumbnail.jpg")).await?,
+            playlist,
+            progress,
+        })
+    }
+}
+
+/// Finds the working directories in `root`, newest first. Directories are
+/// not searched further once they contain an `info.json`, so that segment
+/// directories are not listed.
+pub async fn find_archives(root: &Path, playlist: &str) -> std::io::Result<Vec<Archive>> {
+    let mut archives = Vec::new();
+    let mut dirs = vec![(root.to_path_buf(), 0)];
+    while let Some((dir, depth)) = dirs.pop() {
+        if tokio::fs::try_exists(dir.join("info.json")).await? {
+            match Archive::read(root, &dir, playlist).await {
+                Ok(archive) => archives.push(archive),
+                Err(e) => debug!("Skipping {}: {}", dir.display(), e),
+            }
+            continue;
+        }
+        if depth >= MAX_DEPTH {
+            continue;
+        }
+
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if !hidden && entry.file_type().await?.is_dir() {
+                dirs.push((entry.path(), depth + 1));
+            }
+        }
+    }
+
+    archives.sort_by(|a, b| (b.start_time, &a.path).cmp(&(a.start_time, &b.path)));
+    Ok(archives)
+}
+
+struct ServeState {
+    root: PathBuf,
+    playlist: String,
+}
+
+/// Content types of the files a download produces. Other files get the type
+/// guessed from their extension.
+fn content_type(path: &str) -> Option<&'static str> {
+    match path.rsplit_once('.')?.1 {
+        "m3u8" => Some("application/vnd.apple.mpegurl"),
+        "ts" => Some("video/mp2t"),
+        "mp4" => Some("video/mp4"),
+        _ => None,
+    }
+}
+
+/// Fixes the content type of media files and keeps playlists from being
+/// cached, since they change while a stream is downloading.
+async fn media_headers(req: Request, next: Next) -> Response {
+    let path = req.uri().path().to_string();
+    let mut resp = next.run(req).await;
+    if !resp.status().is_success() {
+        return resp;
+    }
+
+    let headers = resp.headers_mut();
+    if let Some(content_type) = content_type(&path) {
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    }
+    if path.ends_with(".m3u8") {
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    }
+    resp
+}
+
+async fn archives_handler(State(state): State<Arc<ServeState>>) -> Response {
+    match find_archives(&state.root, &state.playlist).await {
+        Ok(archives) => Json(archives).into_response(),
+        Err(e) => {
+            error!("Could not list archives: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Routes that serve the player page at `/`, the archives in `dir` as JSON
+/// at `/_yta/archives`, and the files in `dir` with support for range
+/// requests. `playlist` is the name of the master playlist in each working
+/// directory.
+pub fn router(dir: &Path, playlist: &str) -> Router {
+    let state = Arc::new(ServeState {
+        root: dir.to_path_buf(),
+        playlist: playlist.to_string(),
+    });
+    Router::new()
+        .route("/", get(Html(PLAYER_HTML)))
+        .route(
+            "/_yta/player.js",
+            get(([(header::CONTENT_TYPE, "text/javascript")], PLAYER_JS)),
+        )
+        .route("/_yta/archives", get(archives_handler))
+        .with_state(state)
+        .fallback_service(ServeDir::new(dir))
+        .layer(middleware::from_fn(media_headers))
+}
+
+/// Serves the player and the files in `dir` until `cancel` is triggered,
+/// so that streams can be watched while they are downloading.
+pub async fn serve(
+    listener: TcpListener,
+    dir: &Path,
+    playlist: &str,
+    cancel: CancellationToken,
+) -> std::io::Result<()> {
+    info!(
+        "Serving {} on http://{}",
+        dir.display(),
+        listener.local_addr()?
+    );
+    axum::serve(listener, router(dir, playlist))
+        .with_graceful_shutdown(cancel.cancelled_owned())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serve_files() {
+        let dir = std::env::temp_dir().join(format!("yta-rs-serve-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join("index.m3u8"), "#EXTM3U\n")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("seq_0.f140.mp4"), b"0123456789")
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn({
+            let (dir, cancel) = (dir.clone(), cancel.clone());
+            async move { serve(listener, &dir, "index.m3u8", cancel).await }
+        });
+
+        let client = reqwest::Client::new();
+        let resp = client
+            .get(format!("{}/index.m3u8", base))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE.as_str()],
+            "application/vnd.apple.mpegurl"
+        );
+        assert_eq!(resp.headers()[header::CACHE_CONTROL.as_str()], "no-cache");
+
+        let resp = client
+            .get(format!("{}/seq_0.f140.mp4", base))
+            .header(header::RANGE.as_str(), "bytes=2-5")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 206);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE.as_str()], "video/mp4");
+        assert!(resp.headers().get(header::CACHE_CONTROL.as_str()).is_none());
+        assert_eq!(resp.text().await.unwrap(), "2345");
+
+        let resp = client
+            .get(format!("{}/missing.m3u8", base))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 404);
+
+        let resp = client.get(&base).send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(resp.text().await.unwrap().contains("/_yta/player.js"));
+
+        cancel.cancel();
+        server.await.unwrap().unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn playlist_progress() {
+        let progress = PlaylistProgress::parse(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXTINF:2.0,\nseq_0.ts\n#EXTINF:1.5,\nseq_1.ts\n",
+        );
+        assert_eq!(progress.segments, 2);
+        assert_eq!(progress.duration, 3.5);
+        assert!(!progress.finished);
+        assert!(PlaylistProgress::parse("#EXTM3U\n#EXT-X-ENDLIST\n").finished);
+    }
+
+    #[tokio::test]
+    async fn list_archives() {
+        let root = std::env::temp_dir().join(format!("yta-rs-archives-{}", std::process::id()));
+        let live = root.join("channel/live");
+        let vod = root.join("channel/vod");
+        tokio::fs::create_dir_all(live.join("segments"))
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(&vod).await.unwrap();
+
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/watchpage_live.html");
+        let html = std::fs::read_to_string(d).expect("Could not read watch page");
+        let ipr = crate::player_response::InitialPlayerResponse::from_html(&html).unwrap();
+        let mut info = InfoJson::from_ipr(&ipr);
+        info.write(&vod.join("info.json")).await.unwrap();
+        info.actual_start_time = Some(Utc::now());
+        info.add_gap(3);
+        info.write(&live.join("info.json")).await.unwrap();
+        // Working directories are not searched for more archives
+        info.write(&live.join("segments/info.json")).await.unwrap();
+
+        tokio::fs::write(
+            live.join("index.m3u8"),
+            "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\nindex.f140.m3u8\n",
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(
+            live.join("index.f140.m3u8"),
+            "#EXTM3U\n#EXTINF:2.0,\nsegments/seq_0.f140.mp4\n",
+        )
+        .await
+        .unwrap();
+
+        let archives = find_archives(&root, "index.m3u8").await.unwrap();
+        let summary = archives
+            .iter()
+            .map(|a| (a.path.as_str(), a.playlist.as_deref(), a.gaps))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("channel/live", Some("channel/live/index.m3u8"), 1),
+                ("channel/vod", None, 0),
+            ]
+        );
+        assert_eq!(archives[0].id, "jfKfPfyJRdk");
+        assert_eq!(
+            archives[0].progress,
+            Some(PlaylistProgress {
+                segments: 1,
+                duration: 2.0,
+                finished: false,
+            })
+        );
+
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,426 @@
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    dash, hls,
+    player_response::{AdaptiveFormat, InitialPlayerResponse, PlayerResponseError},
+    util,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum SourceError {
+    #[error("Error getting initial player response")]
+    InitialPlayerResponseError(#[from] PlayerResponseError),
+    #[error("Could not find representation")]
+    MissingRepresentation(String),
+    #[error("Could not download HLS playlist")]
+    DownloadError(#[from] util::DownloadError),
+    #[error("Could not parse HLS playlist")]
+    ParseError(#[from] hls::ParseError),
+}
+
+/// The kind of manifest segments are downloaded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    Dash,
+    Hls,
+}
+
+impl std::str::FromStr for SourceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dash" => Ok(SourceKind::Dash),
+            "hls" => Ok(SourceKind::Hls),
+            _ => Err(format!("Unknown source {}, expected dash or hls", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackKind {
+    Audio,
+    Video,
+    /// Audio and video in one stream, as served by HLS
+    Muxed,
+}
+
+impl TrackKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrackKind::Audio => "audio",
+            TrackKind::Video => "video",
+            TrackKind::Muxed => "muxed",
+        }
+    }
+}
+
+/// A stream of segments in one format
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub id: i64,
+    pub kind: TrackKind,
+    pub codecs: String,
+    pub bandwidth: i64,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub frame_rate: Option<f64>,
+}
+
+impl Track {
+    pub fn extension(&self) -> &'static str {
+        match self.kind {
+            TrackKind::Audio | TrackKind::Video => "mp4",
+            TrackKind::Muxed => "ts",
+        }
+    }
+
+    pub fn segment_fname(&self, seq: i64) -> String {
+        let prefix = match self.kind {
+            TrackKind::Audio => "a",
+            TrackKind::Video => "v",
+            TrackKind::Muxed => "m",
+        };
+        format!("seq_{}.{}{}.{}", seq, prefix, self.id, self.extension())
+    }
+}
+
+impl From<&dash::Representation> for Track {
+    fn from(r: &dash::Representation) -> Self {
+        Self {
+            id: r.id,
+            kind: if r.height.is_some() {
+                TrackKind::Video
+            } else {
+                TrackKind::Audio
+            },
+            codecs: r.codecs.clone(),
+            bandwidth: r.bandwidth,
+            width: r.width,
+            height: r.height,
+            frame_rate: r.frame_rate,
+        }
+    }
+}
+
+impl From<&AdaptiveFormat> for Track {
+    fn from(f: &AdaptiveFormat) -> Self {
+        Self {
+            id: f.itag,
+            kind: if f.is_audio() {
+                TrackKind::Audio
+            } else {
+                TrackKind::Video
+            },
+            codecs: f.mime().map(|m| m.codecs.join(",")).unwrap_or_default(),
+            bandwidth: f.bitrate,
+            width: f.width,
+            height: f.height,
+            frame_rate: f.fps,
+        }
+    }
+}
+
+impl fmt::Display for Track {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            TrackKind::Audio => write!(
+                f,
+                "Audio: {}kbps ({}, f{})",
+                self.bandwidth / 1000,
+                self.codecs,
+                self.id
+            ),
+            TrackKind::Video | TrackKind::Muxed => write!(
+                f,
+                "{}: {}x{} {}fps ({}, f{})",
+                if self.kind == TrackKind::Video {
+                    "Video"
+                } else {
+                    "Muxed"
+                },
+                self.width.unwrap_or_default(),
+                self.height.unwrap_or_default(),
+                self.frame_rate.unwrap_or_default(),
+                self.codecs,
+                self.id,
+            ),
+        }
+    }
+}
+
+/// Which formats to download
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum FormatSelection {
+    /// Highest bitrate audio and video
+    #[default]
+    Best,
+    /// Lowest bitrate audio and video
+    Worst,
+    /// Highest bitrate video up to a height, with the best audio
+    MaxHeight(i64),
+    /// Specific formats by itag, e.g. `299+140`. The first listed format of
+    /// each kind that is available is used.
+    Itags(Vec<i64>),
+}
+
+impl FormatSelection {
+    /// Picks a format from candidates of one kind. `height` and `bandwidth`
+    /// return the properties of a candidate, and `id` its itag.
+    pub fn select<'a, T>(
+        &self,
+        candidates: impl IntoIterator<Item = &'a T>,
+        id: impl Fn(&T) -> Option<i64>,
+        height: impl Fn(&T) -> Option<i64>,
+        bandwidth: impl Fn(&T) -> i64,
+    ) -> Option<&'a T> {
+        let candidates = candidates.into_iter();
+        match self {
+            FormatSelection::Best => candidates.max_by_key(|c| bandwidth(c)),
+            FormatSelection::Worst => candidates.min_by_key(|c| bandwidth(c)),
+            FormatSelection::MaxHeight(max) => candidates
+                .filter(|c| height(c).map(|h| h <= *max).unwrap_or(true))
+                .max_by_key(|c| bandwidth(c)),
+            FormatSelection::Itags(itags) => {
+                let candidates = candidates.collect::<Vec<_>>();
+                itags
+                    .iter()
+                    .find_map(|itag| candidates.iter().find(|c| id(c) == Some(*itag)))
+                    .copied()
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for FormatSelection {
+    type Err = String;
+
+    /// Parses `best`, `worst`, a maximum height such as `720p`, or itags
+    /// joined with `+` or `,`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "best" => Ok(FormatSelection::Best),
+            "worst" => Ok(FormatSelection::Worst),
+            s if s.ends_with('p') => s[..s.len() - 1]
+                .parse()
+                .map(FormatSelection::MaxHeight)
+                .map_err(|_| format!("Invalid height: {}", s)),
+            s => s
+                .split(['+', ','])
+                .map(|i| i.trim().parse())
+                .collect::<Result<Vec<_>, _>>()
+                .map(FormatSelection::Itags)
+                .map_err(|_| format!("Invalid format selection: {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for FormatSelection {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// A segment available for download, with one URL per track
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub seq: i64,
+    pub duration: Duration,
+    pub urls: Vec<String>,
+    /// Wall-clock time the segment starts at, if the manifest gives it
+    pub time: Option<DateTime<Utc>>,
+}
+
+/// Where segments are downloaded from. DASH provides separate audio and
+/// video tracks, while HLS provides a single muxed track.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub kind: SourceKind,
+    pub tracks: Vec<Track>,
+    pub segment_duration: Duration,
+    hls_media_url: Option<String>,
+}
+
+fn select_dash_tracks(
+    manifest: &dash::Manifest,
+    format: &FormatSelection,
+) -> Result<Vec<Track>, SourceError> {
+    let select = |is_video: bool| {
+        format.select(
+            manifest
+                .representations
+                .iter()
+                .filter(|r| r.height.is_some() == is_video),
+            |r| Some(r.id),
+            |r| r.height,
+            |r| r.bandwidth,
+        )
+    };
+
+    let audio = select(false).ok_or(SourceError::MissingRepresentation("audio".to_string()))?;
+    let video = select(true).ok_or(SourceError::MissingRepresentation("video".to_string()))?;
+
+    Ok(vec![audio.into(), video.into()])
+}
+
+fn select_hls_variant<'a>(
+    master: &'a hls::MasterPlaylist,
+    format: &FormatSelection,
+) -> Result<&'a hls::Variant, SourceError> {
+    format
+        .select(
+            &master.variants,
+            |v| v.itag(),
+            |v| v.height,
+            |v| v.bandwidth,
+        )
+        .ok_or(SourceError::MissingRepresentation(
+            "HLS variant".to_string(),
+        ))
+}
+
+impl Source {
+    /// Selects formats from the preferred manifest, falling back to the
+    /// other one if the preferred manifest is not available.
+    pub async fn new(
+        client: &util::HttpClient,
+        ipr: &InitialPlayerResponse,
+        preferred: SourceKind,
+        format: &FormatSelection,
+    ) -> Result<Self, SourceError> {
+        let fallback = match preferred {
+            SourceKind::Dash => SourceKind::Hls,
+            SourceKind::Hls => SourceKind::Dash,
+        };
+
+        match Self::with_kind(client, ipr, preferred, format).await {
+            Err(SourceError::InitialPlayerResponseError(
+                PlayerResponseError::NoDashManifestURL | PlayerResponseError::NoHlsManifestURL,
+            )) => {
+                info!(
+                    "{:?} manifest not available, using {:?}",
+                    preferred, fallback
+                );
+                Self::with_kind(client, ipr, fallback, format).await
+            }
+            res => res,
+        }
+    }
+
+    async fn with_kind(
+        client: &util::HttpClient,
+        ipr: &InitialPlayerResponse,
+        kind: SourceKind,
+        format: &FormatSelection,
+    ) -> Result<Self, SourceError> {
+        match kind {
+            SourceKind::Dash => {
+                let manifest = ipr.get_dash_representations(client).await?;
+                Ok(Self {
+                    kind,
+                    tracks: select_dash_tracks(&manifest, format)?,
+                    segment_duration: Duration::from_millis(manifest.segment_duration as u64),
+                    hls_media_url: None,
+                })
+            }
+            SourceKind::Hls => {
+                let master = ipr.get_hls_master_playlist(client).await?;
+                let variant = select_hls_variant(&master, format)?;
+                let media_url = variant.uri.clone();
+                let media =
+                    hls::parse_media_playlist(&client.fetch_text(&media_url).await?, &media_url)?;
+
+                Ok(Self {
+                    kind,
+                    tracks: vec![Track {
+                        id: variant.itag().unwrap_or_default(),
+                        kind: TrackKind::Muxed,
+                        codecs: variant.codecs.clone().unwrap_or_default(),
+                        bandwidth: variant.bandwidth,
+                        width: variant.width,
+                        height: variant.height,
+                        frame_rate: variant.frame_rate,
+                    }],
+                    segment_duration: media.target_duration,
+                    hls_media_url: Some(media_url),
+                })
+            }
+        }
+    }
+
+    /// Picks up new URLs from a refreshed player response, as the old ones
+    /// eventually expire.
+    pub async fn refresh(
+        &mut self,
+        client: &util::HttpClient,
+        ipr: &InitialPlayerResponse,
+    ) -> Result<(), SourceError> {
+        // DASH manifests are fetched from the player response on every poll
+        if self.kind == SourceKind::Hls {
+            let master = ipr.get_hls_master_playlist(client).await?;
+            let itag = FormatSelection::Itags(vec![self.tracks[0].id]);
+            let variant = select_hls_variant(&master, &itag)?;
+            self.hls_media_url = Some(variant.uri.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Returns every segment from `from` onwards that is currently available.
+    pub async fn poll(
+        &self,
+        client: &util::HttpClient,
+        ipr: &InitialPlayerResponse,
+        from: i64,
+    ) -> Result<Vec<Segment>, SourceError> {
+        match self.kind {
+            SourceKind::Dash => {
+                let manifest = ipr.get_dash_representations(client).await?;
+                let representations = self
+                    .tracks
+                    .iter()
+                    .map(|t| {
+                        manifest
+                            .representations
+                            .iter()
+                            .find(|r| r.id == t.id)
+                            .ok_or(SourceError::MissingRepresentation(t.id.to_string()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok((from..manifest.latest_segment_number)
+                    .map(|seq| Segment {
+                        seq,
+                        duration: self.segment_duration,
+                        urls: representations.iter().map(|r| r.get_url(seq)).collect(),
+                        time: manifest.segment_time(seq),
+                    })
+                    .collect())
+            }
+            SourceKind::Hls => {
+                let media_url = self.hls_media_url.as_deref().unwrap_or_default();
+                let media =
+                    hls::parse_media_playlist(&client.fetch_text(media_url).await?, media_url)?;
+
+                Ok(media
+                    .segments
+                    .into_iter()
+                    .filter(|s| s.sequence >= from)
+                    .map(|s| Segment {
+                        seq: s.sequence,
+                        duration: s.duration,
+                        urls: vec![s.uri],
+                        time: s.program_date_time,
+                    })
+                    .collect())
+            }
+        }
+    }
+}
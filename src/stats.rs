@@ -1,29 +1,310 @@
-use std::io::Write;
+use std::{
+    collections::VecDeque,
+    io::{IsTerminal, Write},
+    time::{Duration, Instant},
+};
 
-use crate::util;
+use serde::Serialize;
 
+use crate::{
+    source::{Track, TrackKind},
+    util,
+};
+
+/// How far back downloads are counted towards the current speed
+const SPEED_WINDOW: Duration = Duration::from_secs(10);
+
+/// Progress of a single download, updated by the worker.
+#[derive(Debug, Clone)]
 pub struct DownloadStatistics {
     pub bytes_downloaded: u64,
     pub segments_downloaded: u64,
+    pub segments_failed: u64,
+    pub segments_total: u64,
+    /// Sequence number of the newest downloaded segment
+    pub last_segment: Option<i64>,
+    segment_duration: Duration,
+    started: Instant,
+    /// Bytes downloaded per segment within the speed window
+    recent: VecDeque<(Instant, u64)>,
+    tracks: Vec<TrackStatistics>,
+}
+
+/// Downloaded segments of one track
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackStatistics {
+    pub id: i64,
+    pub kind: TrackKind,
+    pub segments: u64,
+    pub bytes: u64,
+}
+
+impl TrackStatistics {
+    pub fn average_segment_size(&self) -> Option<u64> {
+        self.bytes.checked_div(self.segments)
+    }
+}
+
+/// A point-in-time copy of the statistics. Durations are in seconds and
+/// speeds in bytes per second.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StatisticsSnapshot {
+    pub elapsed: f64,
+    pub bytes_downloaded: u64,
+    pub segments_downloaded: u64,
+    pub segments_failed: u64,
     pub segments_total: u64,
+    /// Download speed over the last few seconds
+    pub speed: f64,
+    /// How far the newest downloaded segment is behind the live edge
+    pub behind_live: Option<f64>,
+    /// Time until the download catches up with the live edge at the
+    /// current speed, or `None` if it is not catching up
+    pub eta_to_edge: Option<f64>,
+    pub tracks: Vec<TrackSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackSnapshot {
+    #[serde(flatten)]
+    pub track: TrackStatistics,
+    pub average_segment_size: Option<u64>,
 }
 
 impl DownloadStatistics {
-    pub fn new() -> Self {
+    pub fn new(tracks: &[Track], segment_duration: Duration) -> Self {
+        Self::new_at(tracks, segment_duration, Instant::now())
+    }
+
+    fn new_at(tracks: &[Track], segment_duration: Duration, now: Instant) -> Self {
         Self {
             bytes_downloaded: 0,
             segments_downloaded: 0,
+            segments_failed: 0,
             segments_total: 0,
+            last_segment: None,
+            segment_duration,
+            started: now,
+            recent: VecDeque::new(),
+            tracks: tracks
+                .iter()
+                .map(|t| TrackStatistics {
+                    id: t.id,
+                    kind: t.kind,
+                    segments: 0,
+                    bytes: 0,
+                })
+                .collect(),
         }
     }
 
-    pub fn print(&self) {
-        print!(
-            "\x1b[2K\rDownloaded {} of {} segments ({})",
-            self.segments_downloaded,
-            self.segments_total,
-            util::format_bytes(self.bytes_downloaded)
-        );
+    /// Records a downloaded segment, given the bytes downloaded for each
+    /// track. Tracks that were already on disk are passed as 0 and do not
+    /// count towards the average segment size.
+    pub fn add_segment(&mut self, seq: i64, sizes: &[u64]) {
+        self.add_segment_at(seq, sizes, Instant::now());
+    }
+
+    fn add_segment_at(&mut self, seq: i64, sizes: &[u64], now: Instant) {
+        let total = sizes.iter().sum();
+        self.segments_downloaded += 1;
+        self.bytes_downloaded += total;
+        self.last_segment = self.last_segment.max(Some(seq));
+
+        for (track, &size) in self.tracks.iter_mut().zip(sizes) {
+            if size > 0 {
+                track.segments += 1;
+                track.bytes += size;
+            }
+        }
+
+        self.recent.push_back((now, total));
+        self.prune(now);
+    }
+
+    pub fn add_failure(&mut self) {
+        self.segments_failed += 1;
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(&(time, _)) = self.recent.front() {
+            if now.duration_since(time) <= SPEED_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    pub fn snapshot(&self) -> StatisticsSnapshot {
+        self.snapshot_at(Instant::now())
+    }
+
+    fn snapshot_at(&self, now: Instant) -> StatisticsSnapshot {
+        let elapsed = now.duration_since(self.started);
+        let window = elapsed.min(SPEED_WINDOW).as_secs_f64();
+        let recent = self
+            .recent
+            .iter()
+            .filter(|(time, _)| now.duration_since(*time) <= SPEED_WINDOW);
+        let (recent_segments, recent_bytes) =
+            recent.fold((0, 0), |(n, bytes), (_, b)| (n + 1, bytes + b));
+        let (speed, segment_rate) = if window > 0.0 {
+            (
+                recent_bytes as f64 / window,
+                recent_segments as f64 / window,
+            )
+        } else {
+            (0.0, 0.0)
+        };
+
+        // The newest listed segment is the live edge
+        let behind = self.last_segment.map(|last| {
+            (self.segments_total as i64 - 1 - last).max(0) as f64
+                * self.segment_duration.as_secs_f64()
+        });
+
+        // New segments appear once per segment duration, so we only catch
+        // up if we download faster than that
+        let catch_up_rate = segment_rate - 1.0 / self.segment_duration.as_secs_f64();
+        let eta_to_edge = behind.and_then(|behind| {
+            if behind == 0.0 {
+                Some(0.0)
+            } else if catch_up_rate > 0.0 {
+                Some(behind / self.segment_duration.as_secs_f64() / catch_up_rate)
+            } else {
+                None
+            }
+        });
+
+        StatisticsSnapshot {
+            elapsed: elapsed.as_secs_f64(),
+            bytes_downloaded: self.bytes_downloaded,
+            segments_downloaded: self.segments_downloaded,
+            segments_failed: self.segments_failed,
+            segments_total: self.segments_total,
+            speed,
+            behind_live: behind,
+            eta_to_edge,
+            tracks: self
+                .tracks
+                .iter()
+                .map(|t| TrackSnapshot {
+                    average_segment_size: t.average_segment_size(),
+                    track: t.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Shows progress on a single line of the terminal. Does nothing if stdout
+/// is not a terminal, so that redirected output is not filled with escape
+/// codes.
+pub struct ProgressPrinter {
+    enabled: bool,
+}
+
+impl ProgressPrinter {
+    pub fn new() -> Self {
+        Self {
+            enabled: std::io::stdout().is_terminal(),
+        }
+    }
+
+    pub fn print(&self, video_id: &str, snapshot: &StatisticsSnapshot) {
+        if !self.enabled {
+            return;
+        }
+        print!("\x1b[2K\r[{}] {}", video_id, render(snapshot));
         let _ = std::io::stdout().lock().flush();
     }
+
+    /// Moves past the progress line once the download is done.
+    pub fn finish(&self) {
+        if self.enabled {
+            println!();
+        }
+    }
+}
+
+impl Default for ProgressPrinter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Describes the progress in one line, e.g. `Downloaded 10 of 12 segments
+/// (5.00 MiB, 1.00 MiB/s, 4s behind live)`.
+pub fn render(snapshot: &StatisticsSnapshot) -> String {
+    let mut line = format!(
+        "Downloaded {} of {} segments ({}, {}/s",
+        snapshot.segments_downloaded,
+        snapshot.segments_total,
+        util::format_bytes(snapshot.bytes_downloaded),
+        util::format_bytes(snapshot.speed as u64),
+    );
+    if let Some(behind) = snapshot.behind_live.filter(|b| *b > 0.0) {
+        line += &format!(", {:.0}s behind live", behind);
+    }
+    if snapshot.segments_failed > 0 {
+        line += &format!(", {} failed", snapshot.segments_failed);
+    }
+    line.push(')');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: i64, kind: TrackKind) -> Track {
+        Track {
+            id,
+            kind,
+            codecs: String::new(),
+            bandwidth: 0,
+            width: None,
+            height: None,
+            frame_rate: None,
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        let start = Instant::now();
+        let tracks = [track(299, TrackKind::Video), track(140, TrackKind::Audio)];
+        let mut stats = DownloadStatistics::new_at(&tracks, Duration::from_secs(2), start);
+        stats.segments_total = 21;
+
+        // 10 segments in 2 seconds, the first one was already downloaded
+        stats.add_segment_at(0, &[0, 0], start);
+        for seq in 1..10 {
+            let time = start + Duration::from_millis(200 * seq as u64);
+            stats.add_segment_at(seq, &[900, 100], time);
+        }
+        stats.add_failure();
+
+        let snapshot = stats.snapshot_at(start + Duration::from_secs(2));
+        assert_eq!(snapshot.elapsed, 2.0);
+        assert_eq!(snapshot.segments_downloaded, 10);
+        assert_eq!(snapshot.segments_failed, 1);
+        assert_eq!(snapshot.bytes_downloaded, 9000);
+        assert_eq!(snapshot.speed, 4500.0);
+        // Segments 10 to 20 are missing
+        assert_eq!(snapshot.behind_live, Some(22.0));
+        // Downloading 5 segments/s while 0.5 new segments appear per second
+        assert_eq!(snapshot.eta_to_edge, Some(11.0 / 4.5));
+        assert_eq!(snapshot.tracks[0].track.segments, 9);
+        assert_eq!(snapshot.tracks[0].average_segment_size, Some(900));
+        assert_eq!(snapshot.tracks[1].average_segment_size, Some(100));
+        assert_eq!(
+            render(&snapshot),
+            "Downloaded 10 of 21 segments (8.79 KiB, 4.39 KiB/s, 22s behind live, 1 failed)"
+        );
+
+        // Old downloads no longer count towards the speed
+        let snapshot = stats.snapshot_at(start + Duration::from_secs(60));
+        assert_eq!(snapshot.speed, 0.0);
+        assert_eq!(snapshot.eta_to_edge, None);
+    }
 }
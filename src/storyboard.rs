@@ -0,0 +1,279 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use futures::{stream, StreamExt, TryStreamExt};
+
+use crate::util;
+
+/// A set of sprite sheets at one resolution. Each sheet is a grid of
+/// `columns` × `rows` frames of `width` × `height` pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Level {
+    pub width: u32,
+    pub height: u32,
+    /// Total number of frames. Unknown for live storyboards, which keep
+    /// growing while the stream is live.
+    pub count: Option<u32>,
+    pub columns: u32,
+    pub rows: u32,
+    /// Time between frames, if known
+    pub interval: Option<Duration>,
+    url_template: String,
+}
+
+impl Level {
+    pub fn frames_per_sheet(&self) -> u32 {
+        (self.columns * self.rows).max(1)
+    }
+
+    /// Number of sprite sheets, if the frame count is known
+    pub fn sheet_count(&self) -> Option<u32> {
+        self.count.map(|c| c.div_ceil(self.frames_per_sheet()))
+    }
+
+    pub fn sheet_url(&self, sheet: u32) -> String {
+        self.url_template.replace("$M", &sheet.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Storyboard {
+    pub is_live: bool,
+    /// Levels from lowest to highest resolution
+    pub levels: Vec<Level>,
+}
+
+impl Storyboard {
+    /// The highest resolution level
+    pub fn best_level(&self) -> Option<&Level> {
+        self.levels.last()
+    }
+}
+
+fn parse_vod_level(base_url: &str, index: usize, level: &str) -> Option<Level> {
+    // width#height#count#columns#rows#interval#name#sigh
+    let parts = level.split('#').collect::<Vec<_>>();
+    if parts.len() < 8 {
+        return None;
+    }
+
+    let url = base_url
+        .replace("$L", &index.to_string())
+        .replace("$N", parts[6]);
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let count: u32 = parts[2].parse().ok()?;
+    let interval: u64 = parts[5].parse().ok()?;
+
+    Some(Level {
+        width: parts[0].parse().ok()?,
+        height: parts[1].parse().ok()?,
+        count: Some(count),
+        columns: parts[3].parse().ok()?,
+        rows: parts[4].parse().ok()?,
+        interval: Some(Duration::from_millis(interval)).filter(|d| !d.is_zero()),
+        url_template: format!("{}{}sigh={}", url, separator, parts[7]),
+    })
+}
+
+fn parse_live_level(spec: &str) -> Option<Level> {
+    // url#width#height#columns#rows
+    let parts = spec.split('#').collect::<Vec<_>>();
+    if parts.len() < 5 {
+        return None;
+    }
+
+    Some(Level {
+        width: parts[1].parse().ok()?,
+        height: parts[2].parse().ok()?,
+        count: None,
+        columns: parts[3].parse().ok()?,
+        rows: parts[4].parse().ok()?,
+        interval: None,
+        url_template: parts[0].to_string(),
+    })
+}
+
+/// Parses a storyboard spec string from the player response.
+pub fn parse_spec(spec: &str, is_live: bool) -> Option<Storyboard> {
+    let levels = if is_live {
+        vec![parse_live_level(spec)?]
+    } else {
+        let mut parts = spec.split('|');
+        let base_url = parts.next()?;
+        parts
+            .enumerate()
+            .map(|(i, level)| parse_vod_level(base_url, i, level))
+            .collect::<Option<Vec<_>>>()?
+    };
+
+    if levels.is_empty() {
+        return None;
+    }
+
+    Some(Storyboard { is_live, levels })
+}
+
+/// Downloads every sprite sheet of a level into `outdir`, returning the
+/// paths of the saved sheets in order. For live storyboards, sheets are
+/// fetched until one is not found, as it does not exist yet. Other errors
+/// are returned once the client has given up retrying.
+pub async fn download_sheets(
+    client: &util::HttpClient,
+    level: &Level,
+    outdir: &Path,
+    concurrency: usize,
+) -> Result<Vec<PathBuf>, util::DownloadError> {
+    let download = |sheet: u32| async move {
+        let path = outdir.join(format!(
+            "storyboard_{}x{}_M{}.jpg",
+            level.width, level.height, sheet
+        ));
+        let (data, _) = client.fetch_bytes(&level.sheet_url(sheet)).await?;
+        tokio::fs::write(&path, data).await?;
+        Ok::<_, util::DownloadError>(path)
+    };
+
+    match level.sheet_count() {
+        Some(count) => {
+            stream::iter(0..count)
+                .map(download)
+                .buffered(concurrency.max(1))
+                .try_collect()
+                .await
+        }
+        None => {
+            let mut paths = Vec::new();
+            for sheet in 0.. {
+                match download(sheet).await {
+                    Ok(path) => paths.push(path),
+                    Err(util::DownloadError::ReqwestError(e))
+                        if !paths.is_empty()
+                            && e.status() == Some(reqwest::StatusCode::NOT_FOUND) =>
+                    {
+                        break
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(paths)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_vod() {
+        let spec = "https://i.ytimg.com/sb/6u8D5tAzjns/storyboard3_L$L/$N.jpg?sqp=abc|48#27#100#10#10#0#default#rs$AAA|80#45#386#10#10#10000#M$M#rs$BBB|160#90#386#5#5#10000#M$M#rs$CCC";
+        let sb = parse_spec(spec, false).expect("Could not parse spec");
+
+        assert!(!sb.is_live);
+        assert_eq!(sb.levels.len(), 3);
+
+        let level = &sb.levels[0];
+        assert_eq!(level.sheet_count(), Some(1));
+        assert_eq!(level.interval, None);
+        assert_eq!(
+            level.sheet_url(0),
+            "https://i.ytimg.com/sb/6u8D5tAzjns/storyboard3_L0/default.jpg?sqp=abc&sigh=rs$AAA"
+        );
+
+        let level = &sb.levels[2];
+        assert_eq!((level.width, level.height), (160, 90));
+        assert_eq!(level.sheet_count(), Some(16));
+        assert_eq!(level.interval, Some(Duration::from_secs(10)));
+        assert_eq!(
+            level.sheet_url(15),
+            "https://i.ytimg.com/sb/6u8D5tAzjns/storyboard3_L2/M15.jpg?sqp=abc&sigh=rs$CCC"
+        );
+    }
+
+    #[test]
+    fn parse_live() {
+        let spec = "https://i.ytimg.com/sb/jfKfPfyJRdk/storyboard_live_90_2x2_b2/M$M.jpg?rs=AOn4#159#90#2#2";
+        let sb = parse_spec(spec, true).expect("Could not parse spec");
+
+        assert!(sb.is_live);
+        let level = &sb.levels[0];
+        assert_eq!((level.width, level.height), (159, 90));
+        assert_eq!(level.frames_per_sheet(), 4);
+        assert_eq!(level.sheet_count(), None);
+        assert_eq!(
+            level.sheet_url(7),
+            "https://i.ytimg.com/sb/jfKfPfyJRdk/storyboard_live_90_2x2_b2/M7.jpg?rs=AOn4"
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse_spec("https://example.com/", false).is_none());
+        assert!(parse_spec("https://example.com/#1#2", true).is_none());
+    }
+
+    #[tokio::test]
+    async fn download_sheets() {
+        use axum::{http::StatusCode, routing::get, Router};
+
+        let app = Router::new()
+            .route("/M0.jpg", get(|| async { "sheet 0" }))
+            .route("/M1.jpg", get(|| async { "sheet 1" }))
+            .route("/error/M0.jpg", get(|| async { "sheet 0" }))
+            .route(
+                "/error/M1.jpg",
+                get(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "error") }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dir = std::env::temp_dir().join(format!("yta-rs-storyboard-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let client = util::HttpClient::with_options(&util::HttpOptions {
+            retries: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        let level = |template: String, count: Option<u32>| Level {
+            width: 160,
+            height: 90,
+            count,
+            columns: 2,
+            rows: 2,
+            interval: None,
+            url_template: template,
+        };
+
+        // A live storyboard ends at the first sheet that does not exist yet
+        let live = level(format!("{}/M$M.jpg", base), None);
+        let paths = super::download_sheets(&client, &live, &dir, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                dir.join("storyboard_160x90_M0.jpg"),
+                dir.join("storyboard_160x90_M1.jpg")
+            ]
+        );
+        assert_eq!(std::fs::read(&paths[1]).unwrap(), b"sheet 1");
+
+        // Every sheet of a VOD storyboard is needed
+        let vod = level(format!("{}/M$M.jpg", base), Some(12));
+        assert!(super::download_sheets(&client, &vod, &dir, 2)
+            .await
+            .is_err());
+
+        // Server errors are not taken as the end of a live storyboard
+        let error = level(format!("{}/error/M$M.jpg", base), None);
+        assert!(super::download_sheets(&client, &error, &dir, 2)
+            .await
+            .is_err());
+
+        server.abort();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
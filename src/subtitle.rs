@@ -0,0 +1,752 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_aux::prelude::*;
+
+use crate::{hls, info::InfoJson};
+
+/// Chat file written by yt-dlp's `--write-subs` for live streams, which is
+/// picked up from the working directory when muxing.
+pub const CHAT_FILE: &str = "live_chat.json";
+
+#[derive(thiserror::Error, Debug)]
+pub enum SubtitleError {
+    #[error("I/O error")]
+    IoError(#[from] std::io::Error),
+    #[error("No chat message in {0} could be timed")]
+    NoMessages(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SubtitleFormat {
+    /// Danmaku-style scrolling ASS
+    #[default]
+    Ass,
+    WebVtt,
+}
+
+impl SubtitleFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SubtitleFormat::Ass => "ass",
+            SubtitleFormat::WebVtt => "vtt",
+        }
+    }
+}
+
+impl FromStr for SubtitleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ass" => Ok(SubtitleFormat::Ass),
+            "vtt" | "webvtt" => Ok(SubtitleFormat::WebVtt),
+            _ => Err(format!(
+                "unknown subtitle format {}, expected ass or vtt",
+                s
+            )),
+        }
+    }
+}
+
+/// A single chat message, timed relative to the start of the archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub offset: Duration,
+    pub author: String,
+    pub message: String,
+}
+
+impl ChatMessage {
+    /// Creates a message from its wall-clock timestamp. Messages sent before
+    /// the archive started are clamped to the beginning.
+    pub fn from_timestamp(
+        start: DateTime<Utc>,
+        timestamp: DateTime<Utc>,
+        author: String,
+        message: String,
+    ) -> Self {
+        Self {
+            offset: (timestamp - start).to_std().unwrap_or_default(),
+            author,
+            message,
+        }
+    }
+}
+
+pub struct AssOptions {
+    pub width: u32,
+    pub height: u32,
+    pub font_name: String,
+    pub font_size: u32,
+    /// How long a message takes to scroll across the screen
+    pub scroll_duration: Duration,
+    /// How long a message may be held back while every lane is busy before
+    /// it is dropped, so bursts of chat do not fall behind the video
+    pub max_delay: Duration,
+}
+
+impl Default for AssOptions {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            font_name: "Arial".into(),
+            font_size: 48,
+            scroll_duration: Duration::from_secs(8),
+            max_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+fn format_vtt_time(d: Duration) -> String {
+    let ms = d.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+fn format_ass_time(d: Duration) -> String {
+    let cs = d.as_millis() / 10;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        cs / 360_000,
+        cs / 6000 % 60,
+        cs / 100 % 60,
+        cs % 100
+    )
+}
+
+fn escape_vtt(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Escapes text for an ASS dialogue line. libass has no escape for a
+/// backslash, so a word joiner is put after it to break up sequences such as
+/// `\N`, and braces are escaped so they cannot open override blocks.
+fn escape_ass(text: &str) -> String {
+    text.replace('\\', "\\\u{2060}")
+        .replace('{', "\\{")
+        .replace('}', "\\}")
+        .replace('\n', " ")
+}
+
+/// Renders messages as a WebVTT track, with each message shown for
+/// `display` time.
+pub fn to_webvtt(messages: &[ChatMessage], display: Duration) -> String {
+    let mut out = String::from("WEBVTT\n");
+
+    for msg in messages {
+        out.push_str(&format!(
+            "\n{} --> {}\n<v {}>{}\n",
+            format_vtt_time(msg.offset),
+            format_vtt_time(msg.offset + display),
+            escape_vtt(&msg.author),
+            escape_vtt(&msg.message.replace('\n', " ")),
+        ));
+    }
+
+    out
+}
+
+/// Rough on-screen width of a string. Wide (e.g. CJK) characters take up a
+/// full em, everything else about half.
+fn text_width(text: &str, font_size: u32) -> u32 {
+    text.chars()
+        .map(|c| {
+            if c.len_utf8() > 2 {
+                font_size
+            } else {
+                font_size / 2
+            }
+        })
+        .sum()
+}
+
+/// A message scrolling through a lane
+struct Scroller {
+    start: Duration,
+    width: u32,
+    /// Speed in px/s
+    speed: f64,
+}
+
+impl Scroller {
+    /// Earliest time a message of `speed` can enter the lane after this one
+    /// without overlapping it: once this message has fully entered the
+    /// screen, and late enough that a faster message does not catch up
+    /// with it before it leaves.
+    fn clear_at(&self, speed: f64, screen_width: u32, scroll_duration: Duration) -> Duration {
+        let entered = self.start + Duration::from_secs_f64(self.width as f64 / self.speed);
+        let caught_up = (self.start + scroll_duration)
+            .saturating_sub(Duration::from_secs_f64(screen_width as f64 / speed));
+        entered.max(caught_up)
+    }
+}
+
+/// Renders messages as an ASS track where each message scrolls from right to
+/// left across the screen, danmaku-style. Each message goes in the lane it
+/// can enter first without overlapping the message before it there. When
+/// every lane is busy, such as in bursts of chat, it is delayed by up to
+/// `max_delay`, and dropped if no lane frees up by then.
+pub fn to_ass(messages: &[ChatMessage], opts: &AssOptions) -> String {
+    let mut out = format!(
+        "[Script Info]
+ScriptType: v4.00+
+PlayResX: {width}
+PlayResY: {height}
+WrapStyle: 2
+ScaledBorderAndShadow: yes
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Danmaku,{font},{size},&H00FFFFFF,&H00FFFFFF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,0,7,0,0,0,1
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+",
+        width = opts.width,
+        height = opts.height,
+        font = opts.font_name,
+        size = opts.font_size,
+    );
+
+    let line_height = opts.font_size + opts.font_size / 4;
+    let lanes = (opts.height / line_height).max(1) as usize;
+    let scroll_secs = opts.scroll_duration.as_secs_f64();
+
+    // Last message in each lane
+    let mut lanes: Vec<Option<Scroller>> = (0..lanes).map(|_| None).collect();
+    let mut dropped = 0;
+
+    for msg in messages {
+        let text = format!("{}: {}", msg.author, msg.message);
+        let width = text_width(&text, opts.font_size);
+
+        // Speed in px/s to cross the screen plus the message's own width
+        let speed = (opts.width + width) as f64 / scroll_secs;
+
+        let (lane, start) = lanes
+            .iter()
+            .map(|last| match last {
+                Some(last) => {
+                    msg.offset
+                        .max(last.clear_at(speed, opts.width, opts.scroll_duration))
+                }
+                None => msg.offset,
+            })
+            .enumerate()
+            .min_by_key(|(_, start)| *start)
+            .expect("there is always a lane");
+        // Round up to what ASS can represent so the delay is kept
+        let start = Duration::from_millis(start.as_nanos().div_ceil(10_000_000) as u64 * 10);
+        if start > msg.offset + opts.max_delay {
+            dropped += 1;
+            continue;
+        }
+        lanes[lane] = Some(Scroller {
+            start,
+            width,
+            speed,
+        });
+
+        let y = lane as u32 * line_height;
+        out.push_str(&format!(
+            "Dialogue: 0,{},{},Danmaku,,0,0,0,,{{\\move({},{},{},{})}}{}\n",
+            format_ass_time(start),
+            format_ass_time(start + opts.scroll_duration),
+            opts.width,
+            y,
+            -(width as i64),
+            y,
+            escape_ass(&text),
+        ));
+    }
+
+    if dropped > 0 {
+        debug!("Dropped {} chat messages with no free lane", dropped);
+    }
+    out
+}
+
+pub async fn write_webvtt(
+    path: &Path,
+    messages: &[ChatMessage],
+    display: Duration,
+) -> std::io::Result<()> {
+    tokio::fs::write(path, to_webvtt(messages, display)).await
+}
+
+pub async fn write_ass(
+    path: &Path,
+    messages: &[ChatMessage],
+    opts: &AssOptions,
+) -> std::io::Result<()> {
+    tokio::fs::write(path, to_ass(messages, opts)).await
+}
+
+// The parts of yt-dlp's live_chat.json lines that are used
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChatLine {
+    replay_chat_item_action: ReplayChatItemAction,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReplayChatItemAction {
+    actions: Vec<ChatAction>,
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    video_offset_time_msec: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChatAction {
+    add_chat_item_action: Option<AddChatItemAction>,
+}
+
+#[derive(Deserialize)]
+struct AddChatItemAction {
+    item: ChatItem,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChatItem {
+    live_chat_text_message_renderer: Option<MessageRenderer>,
+    live_chat_paid_message_renderer: Option<MessageRenderer>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessageRenderer {
+    author_name: Option<SimpleText>,
+    message: Option<Runs>,
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    timestamp_usec: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SimpleText {
+    simple_text: String,
+}
+
+#[derive(Deserialize)]
+struct Runs {
+    runs: Vec<Run>,
+}
+
+#[derive(Deserialize)]
+struct Run {
+    text: Option<String>,
+    emoji: Option<Emoji>,
+}
+
+#[derive(Deserialize)]
+struct Emoji {
+    #[serde(default)]
+    shortcuts: Vec<String>,
+}
+
+impl Runs {
+    /// Joins the text, with emojis as their first shortcut
+    fn text(&self) -> String {
+        self.runs
+            .iter()
+            .filter_map(|r| match (&r.text, &r.emoji) {
+                (Some(text), _) => Some(text.as_str()),
+                (None, Some(emoji)) => emoji.shortcuts.first().map(|s| s.as_str()),
+                (None, None) => None,
+            })
+            .collect()
+    }
+}
+
+/// Parses a yt-dlp `live_chat.json` file, with one JSON object per line.
+/// Messages are timed from their timestamp if the archive's wall-clock
+/// `start` is known, otherwise from their offset into the video. Lines that
+/// are not chat messages or cannot be parsed are skipped.
+pub fn parse_chat(text: &str, start: Option<DateTime<Utc>>) -> Vec<ChatMessage> {
+    let mut messages = Vec::new();
+
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        let line: ChatLine = match serde_json::from_str(line) {
+            Ok(line) => line,
+            Err(e) => {
+                debug!("Skipping chat line: {}", e);
+                continue;
+            }
+        };
+        let action = line.replay_chat_item_action;
+
+        for item in action
+            .actions
+            .into_iter()
+            .filter_map(|a| a.add_chat_item_action)
+            .map(|a| a.item)
+        {
+            let Some(renderer) = item
+                .live_chat_text_message_renderer
+                .or(item.live_chat_paid_message_renderer)
+            else {
+                continue;
+            };
+
+            let timestamp = renderer
+                .timestamp_usec
+                .and_then(DateTime::<Utc>::from_timestamp_micros);
+            let author = renderer
+                .author_name
+                .map(|a| a.simple_text)
+                .unwrap_or_default();
+            let message = renderer.message.map(|m| m.text()).unwrap_or_default();
+
+            let msg = match (start, timestamp, action.video_offset_time_msec) {
+                (Some(start), Some(timestamp), _) => {
+                    ChatMessage::from_timestamp(start, timestamp, author, message)
+                }
+                (_, _, Some(offset)) => ChatMessage {
+                    offset: Duration::from_millis(offset.max(0) as u64),
+                    author,
+                    message,
+                },
+                _ => continue,
+            };
+            messages.push(msg);
+        }
+    }
+
+    messages.sort_by_key(|m| m.offset);
+    messages
+}
+
+/// Wall-clock time of the first segment in the working directory's master
+/// `playlist`, which is where the muxed archive starts.
+pub async fn archive_start(workdir: &Path, playlist: &str) -> Option<DateTime<Utc>> {
+    let master = tokio::fs::read_to_string(workdir.join(playlist))
+        .await
+        .ok()?;
+    let media = master
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with('#'))?;
+    let media = tokio::fs::read_to_string(workdir.join(media)).await.ok()?;
+    let media = hls::parse_media_playlist(&media, "file:///").ok()?;
+    media.segments.first()?.program_date_time
+}
+
+/// Converts a chat file to a subtitle track in `workdir`, aligned to the
+/// start of the archive, and returns its path. The start is taken from the
+/// downloaded segments, then from the stream's start time.
+pub async fn export_chat(
+    chat: &Path,
+    workdir: &Path,
+    playlist: &str,
+    info: &InfoJson,
+    format: SubtitleFormat,
+) -> Result<PathBuf, SubtitleError> {
+    let start = match archive_start(workdir, playlist).await {
+        Some(start) => Some(start),
+        None => info.actual_start_time,
+    };
+    let text = tokio::fs::read_to_string(chat).await?;
+    let messages = parse_chat(&text, start);
+    if messages.is_empty() {
+        return Err(SubtitleError::NoMessages(chat.to_path_buf()));
+    }
+
+    let path = workdir.join(format!("chat.{}", format.extension()));
+    match format {
+        SubtitleFormat::Ass => write_ass(&path, &messages, &AssOptions::default()).await?,
+        SubtitleFormat::WebVtt => write_webvtt(&path, &messages, Duration::from_secs(5)).await?,
+    }
+    info!(
+        "Wrote {} chat messages to {}",
+        messages.len(),
+        path.display()
+    );
+
+    Ok(path)
+}
+
+/// Exports `chat`, or the chat file in `workdir` if there is one, for muxing.
+/// Failures are logged rather than returned, as the archive can be muxed
+/// without chat.
+pub async fn chat_track(
+    chat: Option<&Path>,
+    workdir: &Path,
+    playlist: &str,
+    info: &InfoJson,
+    format: SubtitleFormat,
+) -> Option<PathBuf> {
+    let chat = match chat {
+        Some(chat) => chat.to_path_buf(),
+        None => Some(workdir.join(CHAT_FILE)).filter(|p| p.exists())?,
+    };
+    match export_chat(&chat, workdir, playlist, info, format).await {
+        Ok(path) => Some(path),
+        Err(e) => {
+            warn!("Could not export chat from {}: {}", chat.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(secs: f64, author: &str, message: &str) -> ChatMessage {
+        ChatMessage {
+            offset: Duration::from_secs_f64(secs),
+            author: author.into(),
+            message: message.into(),
+        }
+    }
+
+    #[test]
+    fn timestamps() {
+        let d = Duration::from_millis(3_723_456);
+        assert_eq!(format_vtt_time(d), "01:02:03.456");
+        assert_eq!(format_ass_time(d), "1:02:03.45");
+        assert_eq!(format_vtt_time(Duration::ZERO), "00:00:00.000");
+    }
+
+    #[test]
+    fn from_timestamp() {
+        let start = DateTime::<Utc>::from_timestamp(1_000, 0).unwrap();
+        let m = ChatMessage::from_timestamp(
+            start,
+            DateTime::<Utc>::from_timestamp(1_005, 0).unwrap(),
+            "a".into(),
+            "b".into(),
+        );
+        assert_eq!(m.offset, Duration::from_secs(5));
+
+        // Messages before the start are clamped
+        let m = ChatMessage::from_timestamp(
+            start,
+            DateTime::<Utc>::from_timestamp(990, 0).unwrap(),
+            "a".into(),
+            "b".into(),
+        );
+        assert_eq!(m.offset, Duration::ZERO);
+    }
+
+    #[test]
+    fn webvtt() {
+        let vtt = to_webvtt(
+            &[msg(1.5, "Alice", "a <b> c"), msg(2.0, "Bob", "hi")],
+            Duration::from_secs(5),
+        );
+        assert!(vtt.starts_with("WEBVTT\n"));
+        assert!(vtt.contains("00:00:01.500 --> 00:00:06.500\n<v Alice>a &lt;b&gt; c\n"));
+        assert!(vtt.contains("00:00:02.000 --> 00:00:07.000\n<v Bob>hi\n"));
+    }
+
+    #[test]
+    fn ass_lanes() {
+        let opts = AssOptions::default();
+        let ass = to_ass(
+            &[msg(0.0, "a", "first"), msg(0.1, "b", "second {x}")],
+            &opts,
+        );
+        let dialogues = ass
+            .lines()
+            .filter(|l| l.starts_with("Dialogue:"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(dialogues.len(), 2);
+        // Overlapping messages should be placed in different lanes
+        assert!(dialogues[0].contains("\\move(1920,0,"));
+        assert!(dialogues[1].contains("\\move(1920,60,"));
+        assert!(dialogues[1].ends_with("b: second \\{x\\}"));
+    }
+
+    #[test]
+    fn parse_chat() {
+        let text = std::fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/test/live_chat.json"),
+        )
+        .unwrap();
+
+        // Timed from the start of the archive
+        let start = DateTime::<Utc>::from_timestamp(1_687_356_246, 0).unwrap();
+        let messages = super::parse_chat(&text, Some(start));
+        assert_eq!(
+            messages,
+            vec![
+                msg(0.0, "Carol", "early"),
+                msg(1.0, "Bob", ""),
+                msg(4.0, "Alice", "first :wave:"),
+            ]
+        );
+
+        // Timed from the offset into the video
+        let messages = super::parse_chat(&text, None);
+        assert_eq!(messages[1], msg(1.0, "Bob", ""));
+        assert_eq!(messages[2], msg(4.0, "Alice", "first :wave:"));
+    }
+
+    #[tokio::test]
+    async fn archive_start() {
+        let dir = std::env::temp_dir().join(format!("yta-rs-subtitle-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(
+            dir.join("index.m3u8"),
+            "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\nindex.f299.m3u8\n",
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(
+            dir.join("index.f299.m3u8"),
+            "#EXTM3U\n#EXT-X-TARGETDURATION:5\n\
+             #EXT-X-PROGRAM-DATE-TIME:2023-06-21T14:04:06.500Z\n#EXTINF:5.0,\nseq_3.f299.mp4\n",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            super::archive_start(&dir, "index.m3u8").await,
+            DateTime::<Utc>::from_timestamp_millis(1_687_356_246_500)
+        );
+        assert_eq!(super::archive_start(&dir, "missing.m3u8").await, None);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn ass_escape() {
+        assert_eq!(
+            escape_ass("a\\Nb {\\b1}\nc"),
+            "a\\\u{2060}Nb \\{\\\u{2060}b1\\} c"
+        );
+    }
+
+    /// Start time, lane and width of each dialogue line
+    fn dialogues(ass: &str, opts: &AssOptions) -> Vec<(f64, u32, u32)> {
+        ass.lines()
+            .filter_map(|l| l.strip_prefix("Dialogue: 0,"))
+            .map(|l| {
+                let (start, rest) = l.split_once(',').unwrap();
+                let mut parts = start.split([':', '.']).map(|p| p.parse::<f64>().unwrap());
+                let (h, m, s, cs) = (
+                    parts.next().unwrap(),
+                    parts.next().unwrap(),
+                    parts.next().unwrap(),
+                    parts.next().unwrap(),
+                );
+                let args = rest.split_once("\\move(").unwrap().1;
+                let args = args.split_once(')').unwrap().0;
+                let args = args
+                    .split(',')
+                    .map(|a| a.parse::<i64>().unwrap())
+                    .collect::<Vec<_>>();
+                let lane = args[1] as u32 / (opts.font_size + opts.font_size / 4);
+                (
+                    h * 3600.0 + m * 60.0 + s + cs / 100.0,
+                    lane,
+                    -args[2] as u32,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ass_burst() {
+        let opts = AssOptions {
+            height: 240,
+            ..AssOptions::default()
+        };
+        let lanes = 4;
+
+        // Many more messages at once than there are lanes
+        let messages = (0..100)
+            .map(|_| msg(10.0, "user", "a burst of chat"))
+            .collect::<Vec<_>>();
+        let lines = dialogues(&to_ass(&messages, &opts), &opts);
+
+        // Messages are only held back up to the limit, and the rest dropped
+        assert!(lines.len() > lanes);
+        assert!(lines.len() < messages.len());
+        for line in &lines {
+            assert!(line.0 >= 10.0);
+            assert!(line.0 <= 10.0 + opts.max_delay.as_secs_f64());
+        }
+
+        // Later messages are not pushed back by the dropped ones
+        let mut messages = messages;
+        messages.push(msg(60.0, "user", "later"));
+        let lines = dialogues(&to_ass(&messages, &opts), &opts);
+        assert_eq!(lines.last().unwrap().0, 60.0);
+    }
+
+    #[test]
+    fn ass_no_overlap() {
+        let opts = AssOptions {
+            height: 240,
+            max_delay: Duration::from_secs(3600),
+            ..AssOptions::default()
+        };
+        let scroll = opts.scroll_duration.as_secs_f64();
+
+        // A burst of messages, as when chat replay catches up, with long
+        // (fast) messages following short (slow) ones
+        let messages = (0..40)
+            .map(|i| {
+                let text = if i % 2 == 0 {
+                    "hi"
+                } else {
+                    "a much longer message"
+                };
+                msg(10.0 + i as f64 * 0.05, "user", text)
+            })
+            .collect::<Vec<_>>();
+        let lines = dialogues(&to_ass(&messages, &opts), &opts);
+        assert_eq!(lines.len(), messages.len());
+
+        // Messages are never shown before they were sent
+        for (line, msg) in lines.iter().zip(&messages) {
+            assert!(line.0 + 0.01 >= msg.offset.as_secs_f64());
+        }
+
+        // Position of the left and right edge of a message at a time
+        let edges = |(start, _, width): (f64, u32, u32), t: f64| {
+            let speed = (opts.width + width) as f64 / scroll;
+            let left = opts.width as f64 - (t - start) * speed;
+            (left, left + width as f64)
+        };
+        for (i, a) in lines.iter().enumerate() {
+            for b in lines.iter().skip(i + 1).filter(|b| b.1 == a.1) {
+                let from = a.0.max(b.0);
+                let to = (a.0 + scroll).min(b.0 + scroll);
+                let mut t = from;
+                while t < to {
+                    let (a_left, a_right) = edges(*a, t);
+                    let (b_left, b_right) = edges(*b, t);
+                    assert!(
+                        a_right <= b_left + 1.0 || b_right <= a_left + 1.0,
+                        "{:?} and {:?} overlap at {}",
+                        a,
+                        b,
+                        t
+                    );
+                    t += 0.05;
+                }
+            }
+        }
+    }
+}
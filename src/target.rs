@@ -0,0 +1,192 @@
+use std::{fmt, str::FromStr};
+
+use crate::channel::Channel;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum TargetError {
+    #[error("No video or channel given")]
+    Empty,
+    #[error("Invalid video ID {0:?}, expected 11 characters of A-Z, a-z, 0-9, - or _")]
+    InvalidVideoId(String),
+    #[error("Not a YouTube URL: {0}")]
+    NotYouTube(String),
+    #[error("Unsupported YouTube URL {0}, expected a video or channel URL")]
+    UnsupportedUrl(String),
+}
+
+/// What to download: a single video, or the current stream of a channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Video(String),
+    Channel(Channel),
+}
+
+pub fn is_video_id(id: &str) -> bool {
+    id.len() == 11
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn video(id: &str) -> Result<Target, TargetError> {
+    if is_video_id(id) {
+        Ok(Target::Video(id.to_string()))
+    } else {
+        Err(TargetError::InvalidVideoId(id.to_string()))
+    }
+}
+
+impl Target {
+    /// The canonical URL of the target. For channels, this is the page that
+    /// shows the current live stream.
+    pub fn url(&self) -> String {
+        match self {
+            Target::Video(id) => format!("https://www.youtube.com/watch?v={}", id),
+            Target::Channel(channel) => channel.live_url(),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = TargetError;
+
+    /// Parses watch, `/live/`, `/shorts/` and embed URLs, `youtu.be` links,
+    /// channel URLs, and bare video IDs, channel IDs and handles.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(TargetError::Empty);
+        }
+        if is_video_id(s) {
+            return video(s);
+        }
+
+        // Accept URLs without a scheme, e.g. `youtu.be/jfKfPfyJRdk`
+        let url = match url::Url::parse(s) {
+            Ok(url) => url,
+            Err(_) if s.contains('/') && !s.starts_with('/') => {
+                url::Url::parse(&format!("https://{}", s))
+                    .map_err(|_| TargetError::UnsupportedUrl(s.to_string()))?
+            }
+            Err(_) => {
+                return Channel::parse(s)
+                    .map(Target::Channel)
+                    .ok_or_else(|| TargetError::InvalidVideoId(s.to_string()));
+            }
+        };
+
+        let host = url.host_str().unwrap_or_default();
+        let host = host.strip_prefix("www.").unwrap_or(host);
+        let mut segments = url.path_segments().into_iter().flatten();
+
+        match host {
+            "youtu.be" => video(segments.next().unwrap_or_default()),
+            "youtube.com" | "m.youtube.com" | "music.youtube.com" | "youtube-nocookie.com" => {
+                match segments.next().unwrap_or_default() {
+                    "watch" => video(
+                        &url.query_pairs()
+                            .find(|(k, _)| k == "v")
+                            .map(|(_, v)| v.into_owned())
+                            .unwrap_or_default(),
+                    ),
+                    "live" | "shorts" | "embed" | "v" => video(segments.next().unwrap_or_default()),
+                    _ => Channel::parse(url.as_str())
+                        .map(Target::Channel)
+                        .ok_or_else(|| TargetError::UnsupportedUrl(s.to_string())),
+                }
+            }
+            _ => Err(TargetError::NotYouTube(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Video(id) => write!(f, "{}", id),
+            Target::Channel(channel) => write!(f, "{}", channel),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video_id(s: &str) -> Option<String> {
+        match s.parse() {
+            Ok(Target::Video(id)) => Some(id),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parse_videos() {
+        let id = Some("jfKfPfyJRdk".to_string());
+        assert_eq!(video_id("jfKfPfyJRdk"), id);
+        assert_eq!(video_id("https://www.youtube.com/watch?v=jfKfPfyJRdk"), id);
+        assert_eq!(
+            video_id("https://www.youtube.com/watch?feature=share&v=jfKfPfyJRdk&t=10"),
+            id
+        );
+        assert_eq!(video_id("https://m.youtube.com/watch?v=jfKfPfyJRdk"), id);
+        assert_eq!(video_id("youtube.com/watch?v=jfKfPfyJRdk"), id);
+        assert_eq!(video_id("https://youtu.be/jfKfPfyJRdk?si=abc"), id);
+        assert_eq!(video_id("youtu.be/jfKfPfyJRdk"), id);
+        assert_eq!(
+            video_id("https://www.youtube.com/live/jfKfPfyJRdk?feature=share"),
+            id
+        );
+        assert_eq!(video_id("https://www.youtube.com/shorts/jfKfPfyJRdk"), id);
+        assert_eq!(video_id("https://www.youtube.com/embed/jfKfPfyJRdk"), id);
+        assert_eq!(
+            video_id("https://www.youtube-nocookie.com/embed/jfKfPfyJRdk"),
+            id
+        );
+
+        assert_eq!(
+            Target::Video("jfKfPfyJRdk".to_string()).url(),
+            "https://www.youtube.com/watch?v=jfKfPfyJRdk"
+        );
+    }
+
+    #[test]
+    fn parse_channels() {
+        let handle = Target::Channel(Channel::Handle("@MinatoAqua".to_string()));
+        assert_eq!("@MinatoAqua".parse(), Ok(handle.clone()));
+        assert_eq!(
+            "https://www.youtube.com/@MinatoAqua/live".parse(),
+            Ok(handle.clone())
+        );
+        assert_eq!(
+            "https://www.youtube.com/channel/UC1opHUrw8rvnsadT-iGp7Cg".parse(),
+            Ok(Target::Channel(Channel::Id(
+                "UC1opHUrw8rvnsadT-iGp7Cg".to_string()
+            )))
+        );
+        assert_eq!(handle.url(), "https://www.youtube.com/@MinatoAqua/live");
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!("".parse::<Target>(), Err(TargetError::Empty));
+        assert_eq!(
+            "jfKfPfyJRd".parse::<Target>(),
+            Err(TargetError::InvalidVideoId("jfKfPfyJRd".to_string()))
+        );
+        assert_eq!(
+            "https://www.youtube.com/watch?v=abc".parse::<Target>(),
+            Err(TargetError::InvalidVideoId("abc".to_string()))
+        );
+        assert_eq!(
+            "https://example.com/watch?v=jfKfPfyJRdk".parse::<Target>(),
+            Err(TargetError::NotYouTube(
+                "https://example.com/watch?v=jfKfPfyJRdk".to_string()
+            ))
+        );
+        assert!(matches!(
+            "https://www.youtube.com/feed/subscriptions".parse::<Target>(),
+            Err(TargetError::UnsupportedUrl(_))
+        ));
+    }
+}
@@ -0,0 +1,259 @@
+use std::path::PathBuf;
+
+use crate::{info::InfoJson, player_response::InitialPlayerResponse};
+
+/// Directory the fragments and metadata of a stream are written to
+pub const DEFAULT_WORKDIR: &str = "{id}";
+
+/// File the stream is muxed to
+pub const DEFAULT_OUTPUT: &str = "{start_date} {title} [{id}].mp4";
+
+/// Most filesystems limit names to 255 bytes. Some room is left for suffixes
+/// such as `.part` or `.tmp`.
+const MAX_COMPONENT_BYTES: usize = 240;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    #[error("Unknown template field {{{0}}}")]
+    UnknownField(String),
+    #[error("Unclosed {{ in template")]
+    Unclosed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Id,
+    Title,
+    Channel,
+    ChannelId,
+    StartDate,
+    UploadDate,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "id" => Field::Id,
+            "title" => Field::Title,
+            "channel" => Field::Channel,
+            "channel_id" => Field::ChannelId,
+            "start_date" => Field::StartDate,
+            "upload_date" => Field::UploadDate,
+            _ => return None,
+        })
+    }
+
+    fn value(&self, info: &InfoJson) -> String {
+        match self {
+            Field::Id => info.id.clone(),
+            Field::Title => info.title.clone(),
+            Field::Channel => info.channel.clone(),
+            Field::ChannelId => info.channel_id.clone(),
+            // When the stream actually started, or else when it is scheduled
+            Field::StartDate => info
+                .actual_start_time
+                .map(|t| t.timestamp())
+                .or(info.release_timestamp)
+                .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+                .map(|t| t.format("%Y%m%d").to_string())
+                .or_else(|| info.upload_date.clone())
+                .unwrap_or_default(),
+            Field::UploadDate => info.upload_date.clone().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// A file or directory name template such as `{channel}/{start_date} {title}`.
+/// Fields are replaced by their filesystem-safe value, while `/` in the
+/// template itself separates directories. `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn new(template: &str) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(TemplateError::Unclosed),
+                        }
+                    }
+                    let field = Field::parse(&name).ok_or(TemplateError::UnknownField(name))?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Renders the template, truncating each path component to a length
+    /// most filesystems accept.
+    pub fn render(&self, info: &InfoJson) -> PathBuf {
+        let rendered = self
+            .parts
+            .iter()
+            .map(|p| match p {
+                Part::Literal(s) => s.clone(),
+                Part::Field(f) => sanitize(&f.value(info)),
+            })
+            .collect::<String>();
+
+        let root = if rendered.starts_with('/') { "/" } else { "" };
+        std::iter::once(root.to_string())
+            .chain(
+                rendered
+                    .split('/')
+                    .filter(|c| !c.is_empty())
+                    .map(|c| truncate(c, MAX_COMPONENT_BYTES)),
+            )
+            .collect()
+    }
+
+    /// Renders the template from a player response, e.g. to get the working
+    /// directory to pass to `worker::start`.
+    pub fn render_ipr(&self, ipr: &InitialPlayerResponse) -> PathBuf {
+        self.render(&InfoJson::from_ipr(ipr))
+    }
+}
+
+impl std::str::FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = TemplateError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::new(&s)
+    }
+}
+
+/// Replaces characters that are not allowed in file names on common
+/// filesystems, and strips leading and trailing dots and whitespace.
+pub fn sanitize(s: &str) -> String {
+    let s = s
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect::<String>();
+
+    let s = s.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if s.is_empty() {
+        "_".to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+/// Truncates a file name to at most `max` bytes on a character boundary,
+/// keeping a short extension if there is one.
+fn truncate(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() <= 8 => (stem, &name[stem.len()..]),
+        _ => (name, ""),
+    };
+
+    let mut end = max.saturating_sub(ext.len()).min(stem.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", stem[..end].trim_end(), ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_info(fname: &str) -> InfoJson {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/");
+        d.push(fname);
+        let html =
+            std::fs::read_to_string(d).unwrap_or_else(|_| panic!("Could not read {}", fname));
+        InfoJson::from_ipr(&InitialPlayerResponse::from_html(&html).expect("Could not parse IPR"))
+    }
+
+    #[test]
+    fn render() {
+        let mut info = get_test_info("watchpage_live.html");
+        info.title = "a/b: c?".to_string();
+
+        let template = Template::new("{channel_id}/{upload_date} {title} [{id}].mp4").unwrap();
+        assert_eq!(
+            template.render(&info),
+            PathBuf::from(format!(
+                "{}/20220712 a_b_ c_ [jfKfPfyJRdk].mp4",
+                info.channel_id
+            ))
+        );
+
+        let template = Template::new("/data/{{{id}}}").unwrap();
+        assert_eq!(template.render(&info), PathBuf::from("/data/{jfKfPfyJRdk}"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            Template::new("{nope}"),
+            Err(TemplateError::UnknownField("nope".to_string()))
+        );
+        assert_eq!(Template::new("{id"), Err(TemplateError::Unclosed));
+    }
+
+    #[test]
+    fn sanitize_and_truncate() {
+        assert_eq!(sanitize("..hidden.."), "hidden");
+        assert_eq!(sanitize("a\nb"), "a b");
+        assert_eq!(sanitize("  "), "_");
+
+        let long = "あ".repeat(100) + ".mp4";
+        let truncated = truncate(&long, 100);
+        assert!(truncated.len() <= 100);
+        assert!(truncated.ends_with("あ.mp4"));
+        assert_eq!(truncate("short.mp4", 100), "short.mp4");
+    }
+}
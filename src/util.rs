@@ -1,15 +1,58 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use reqwest_cookie_store::CookieStoreMutex;
-use reqwest_middleware::ClientWithMiddleware;
+use futures::future::try_join_all;
+use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use reqwest_middleware::{ClientWithMiddleware, Middleware, Next};
 use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
-use tokio::{fs::File, io::AsyncWriteExt, try_join};
+use tokio::{fs::File, io::AsyncWriteExt};
 
-use crate::dash::Representation;
+use crate::source::{Segment, Track};
 
 pub struct HttpClient {
     pub client: ClientWithMiddleware,
     pub cookies: Arc<CookieStoreMutex>,
+    pub statuses: Arc<StatusCounts>,
+}
+
+/// Number of responses received for each HTTP status code, including
+/// requests that were retried.
+#[derive(Debug, Default)]
+pub struct StatusCounts(Mutex<BTreeMap<u16, u64>>);
+
+impl StatusCounts {
+    pub fn get(&self) -> BTreeMap<u16, u64> {
+        self.0.lock().expect("Status counts poisoned").clone()
+    }
+
+    fn add(&self, status: reqwest::StatusCode) {
+        *self
+            .0
+            .lock()
+            .expect("Status counts poisoned")
+            .entry(status.as_u16())
+            .or_default() += 1;
+    }
+}
+
+struct CountStatus(Arc<StatusCounts>);
+
+#[async_trait::async_trait]
+impl Middleware for CountStatus {
+    async fn handle(
+        &self,
+        req: reqwest::Request,
+        extensions: &mut task_local_extensions::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        let resp = next.run(req, extensions).await?;
+        self.0.add(resp.status());
+        Ok(resp)
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -20,28 +63,168 @@ pub enum DownloadError {
     ReqwestMiddlewareError(#[from] reqwest_middleware::Error),
     #[error("io error: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("Expected partial content for bytes {start}-{end}, got {status}")]
+    NotPartialContent {
+        start: u64,
+        end: u64,
+        status: reqwest::StatusCode,
+    },
+    #[error("Expected {expected} bytes for bytes {start}-{end}, got {actual}")]
+    WrongLength {
+        start: u64,
+        end: u64,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpOptions {
+    /// Proxy for all requests, e.g. `socks5://127.0.0.1:1080`
+    pub proxy: Option<String>,
+    /// Cookies in the Netscape `cookies.txt` format, as exported by browser
+    /// extensions and yt-dlp
+    pub cookies: Option<PathBuf>,
+    /// How often requests are retried after transient errors
+    pub retries: u32,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            cookies: None,
+            retries: 3,
+        }
+    }
+}
+
+/// Deserializes a duration given in seconds, e.g. `60` or `0.5`.
+pub(crate) fn deserialize_secs<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let secs = <f64 as serde::Deserialize>::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+}
+
+/// Whether `path` is relative and stays inside the directory it is joined
+/// to, with no `..` components.
+pub fn is_subpath(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Deserializes a path that must stay inside the directory it is joined to.
+pub(crate) fn deserialize_subpath<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let path = <Option<PathBuf> as serde::Deserialize>::deserialize(deserializer)?;
+    match path {
+        Some(path) if !is_subpath(&path) => Err(serde::de::Error::custom(format!(
+            "{} must be a relative path without ..",
+            path.display()
+        ))),
+        path => Ok(path),
+    }
+}
+
+/// Loads cookies from a Netscape `cookies.txt` file.
+pub fn load_cookies(path: &Path) -> Result<CookieStore, std::io::Error> {
+    let mut store = CookieStore::default();
+    for line in std::fs::read_to_string(path)?.lines() {
+        // HttpOnly cookies are prefixed like a comment
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+
+        // domain, include subdomains, path, secure, expiry, name, value
+        let fields = line.split('\t').collect::<Vec<_>>();
+        if fields.len() != 7 {
+            warn!("Skipping invalid cookie line in {}", path.display());
+            continue;
+        }
+
+        let mut cookie = format!(
+            "{}={}; Domain={}; Path={}",
+            fields[5], fields[6], fields[0], fields[2]
+        );
+        if fields[3].eq_ignore_ascii_case("TRUE") {
+            cookie.push_str("; Secure");
+        }
+        if let Some(expires) = fields[4]
+            .parse()
+            .ok()
+            .filter(|e| *e > 0)
+            .and_then(|e| chrono::DateTime::from_timestamp(e, 0))
+        {
+            cookie.push_str(&format!(
+                "; Expires={}",
+                expires.format("%a, %d %b %Y %H:%M:%S GMT")
+            ));
+        }
+
+        let url = format!("https://{}{}", fields[0].trim_start_matches('.'), fields[2]);
+        match url::Url::parse(&url) {
+            Ok(url) => {
+                if let Err(e) = store.parse(&cookie, &url) {
+                    debug!("Skipping cookie {}: {}", fields[5], e);
+                }
+            }
+            Err(_) => warn!("Skipping cookie with invalid domain {}", fields[0]),
+        }
+    }
+
+    Ok(store)
 }
 
 impl HttpClient {
     pub fn new() -> reqwest::Result<HttpClient> {
-        let cookies = Arc::new(CookieStoreMutex::default());
-        let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
+        Self::build(CookieStore::default(), &HttpOptions::default())
+    }
+
+    pub fn with_options(options: &HttpOptions) -> Result<HttpClient, DownloadError> {
+        let cookies = match &options.cookies {
+            Some(path) => load_cookies(path)?,
+            None => CookieStore::default(),
+        };
+        Ok(Self::build(cookies, options)?)
+    }
 
-        let client = reqwest::Client::builder()
-            .cookie_provider(cookies.clone())
-            .build()?;
+    fn build(cookies: CookieStore, options: &HttpOptions) -> reqwest::Result<HttpClient> {
+        let cookies = Arc::new(CookieStoreMutex::new(cookies));
+        let retry_policy = ExponentialBackoff::builder().build_with_max_retries(options.retries);
 
+        let mut builder = reqwest::Client::builder().cookie_provider(cookies.clone());
+        if let Some(proxy) = &options.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        let client = builder.build()?;
+
+        // Statuses are counted below the retries to see every attempt
+        let statuses = Arc::new(StatusCounts::default());
         let client = reqwest_middleware::ClientBuilder::new(client)
             .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+            .with(CountStatus(statuses.clone()))
             .build();
 
-        Ok(HttpClient { client, cookies })
+        Ok(HttpClient {
+            client,
+            cookies,
+            statuses,
+        })
     }
 
+    /// Downloads a URL to `path` through a temporary file, returning its
+    /// size. Unsuccessful status codes are treated as errors.
     pub async fn download_file(&self, url: &str, path: &str) -> Result<usize, DownloadError> {
+        // Error pages must not end up on disk as segments
+        let mut resp = self.client.get(url).send().await?.error_for_status()?;
         let temp_path = format!("{}.tmp", path);
         let mut file = File::create(&temp_path).await?;
-        let mut resp = self.client.get(url).send().await?;
         let mut size = 0;
 
         while let Some(chunk) = resp.chunk().await? {
@@ -55,6 +238,57 @@ impl HttpClient {
         Ok(size)
     }
 
+    /// Fetches a URL into memory, returning the body and its content type.
+    /// Unsuccessful status codes are treated as errors.
+    pub async fn fetch_bytes(&self, url: &str) -> Result<(Vec<u8>, Option<String>), DownloadError> {
+        let resp = self.client.get(url).send().await?.error_for_status()?;
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let bytes = resp.bytes().await?;
+
+        Ok((bytes.to_vec(), content_type))
+    }
+
+    /// Fetches the inclusive byte range `start..=end` of a URL into memory.
+    /// Servers that ignore the range or return less than asked for are
+    /// treated as errors.
+    pub async fn fetch_range(
+        &self,
+        url: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, DownloadError> {
+        let resp = self
+            .client
+            .get(url)
+            .header(reqwest::header::RANGE, format!("bytes={}-{}", start, end))
+            .send()
+            .await?
+            .error_for_status()?;
+        if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err(DownloadError::NotPartialContent {
+                start,
+                end,
+                status: resp.status(),
+            });
+        }
+
+        let data = resp.bytes().await?;
+        let expected = end - start + 1;
+        if data.len() as u64 != expected {
+            return Err(DownloadError::WrongLength {
+                start,
+                end,
+                expected,
+                actual: data.len() as u64,
+            });
+        }
+        Ok(data.to_vec())
+    }
+
     pub async fn fetch_text(&self, url: &str) -> Result<String, DownloadError> {
         self.client
             .get(url)
@@ -66,46 +300,62 @@ impl HttpClient {
     }
 }
 
-pub async fn download_av_segment(
+/// Downloads a segment of every track into `outdir`, returning the file
+/// names and the number of bytes downloaded for each track. Files that
+/// already exist are not downloaded again.
+pub async fn download_segment(
     client: &HttpClient,
     outdir: &Path,
-    audio: &Representation,
-    video: &Representation,
-    seq: i64,
-) -> Result<(String, String, usize), DownloadError> {
-    let (url_audio, url_video) = (audio.get_url(seq), video.get_url(seq));
-    let (fname_audio, fname_video) = (
-        format!("seq_{:.6}.a{}.mp4", seq, audio.id),
-        format!("seq_{:.6}.v{}.mp4", seq, video.id),
-    );
-
-    let dl_audio = async {
-        let path_audio = outdir.join(&fname_audio);
-        if let Ok(res) = tokio::fs::try_exists(&path_audio).await {
-            if res {
-                return Ok(0);
+    tracks: &[Track],
+    segment: &Segment,
+) -> Result<(Vec<String>, Vec<u64>), DownloadError> {
+    let downloads = tracks
+        .iter()
+        .zip(&segment.urls)
+        .map(|(track, url)| async move {
+            let fname = track.segment_fname(segment.seq);
+            let path = outdir.join(&fname);
+            if let Ok(true) = tokio::fs::try_exists(&path).await {
+                return Ok((fname, 0));
             }
-        }
 
-        client
-            .download_file(&url_audio, &path_audio.to_string_lossy())
-            .await
-    };
-    let dl_video = async {
-        let path_video = outdir.join(&fname_video);
-        if let Ok(res) = tokio::fs::try_exists(&path_video).await {
-            if res {
-                return Ok(0);
-            }
-        }
+            let size = client.download_file(url, &path.to_string_lossy()).await?;
+            Ok::<_, DownloadError>((fname, size as u64))
+        });
 
-        client
-            .download_file(&url_video, &path_video.to_string_lossy())
-            .await
-    };
-    let (sz_audio, sz_video) = try_join!(dl_audio, dl_video)?;
+    Ok(try_join_all(downloads).await?.into_iter().unzip())
+}
 
-    Ok((fname_audio, fname_video, sz_audio + sz_video))
+/// Guesses the file extension of an image, preferring its magic bytes over
+/// the reported content type.
+pub fn image_extension(content_type: Option<&str>, data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        return Some("jpg");
+    }
+    if data.starts_with(b"\x89PNG") {
+        return Some("png");
+    }
+    if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return Some("webp");
+    }
+
+    match content_type?.split(';').next()?.trim() {
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/webp" => Some("webp"),
+        _ => None,
+    }
+}
+
+/// Directory for cached files such as the player JS, following the XDG base
+/// directory spec.
+pub fn cache_dir() -> std::path::PathBuf {
+    std::env::var_os("XDG_CACHE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(std::path::PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("yta-rs")
 }
 
 pub fn format_bytes(bytes: u64) -> String {
@@ -131,3 +381,144 @@ pub fn format_bytes(bytes: u64) -> String {
 
     format!("{:.2} {}", bytes, suffix)
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn image_extension() {
+        assert_eq!(
+            super::image_extension(None, &[0xff, 0xd8, 0xff, 0xe0]),
+            Some("jpg")
+        );
+        assert_eq!(super::image_extension(None, b"\x89PNG\r\n"), Some("png"));
+        assert_eq!(
+            super::image_extension(Some("image/jpeg"), b"RIFF\0\0\0\0WEBPVP8 "),
+            Some("webp")
+        );
+        assert_eq!(
+            super::image_extension(Some("image/webp; charset=binary"), b""),
+            Some("webp")
+        );
+        assert_eq!(super::image_extension(Some("text/html"), b"<html>"), None);
+    }
+
+    #[test]
+    fn load_cookies() {
+        let path = std::env::temp_dir().join(format!("yta-rs-cookies-{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "# Netscape HTTP Cookie File\n\
+             .youtube.com\tTRUE\t/\tTRUE\t0\tPREF\tf6=40000000\n\
+             #HttpOnly_.youtube.com\tTRUE\t/\tTRUE\t4102444800\tSID\tabc\n\
+             invalid line\n",
+        )
+        .unwrap();
+        let store = super::load_cookies(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let url = url::Url::parse("https://www.youtube.com/watch").unwrap();
+        let mut cookies = store
+            .matches(&url)
+            .iter()
+            .map(|c| format!("{}={}", c.name(), c.value()))
+            .collect::<Vec<_>>();
+        cookies.sort();
+        assert_eq!(cookies, vec!["PREF=f6=40000000", "SID=abc"]);
+        assert!(store
+            .matches(&url::Url::parse("http://www.youtube.com/").unwrap())
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn download_file_status() {
+        use axum::{http::StatusCode, routing::get, Router};
+
+        let app = Router::new()
+            .route("/ok", get(|| async { "0123456789" }))
+            .route(
+                "/missing",
+                get(|| async { (StatusCode::NOT_FOUND, "not found") }),
+            )
+            .route(
+                "/error",
+                get(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "error") }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dir = std::env::temp_dir().join(format!("yta-rs-download-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let out = dir.join("out.mp4");
+        let out = out.to_str().unwrap();
+
+        // Server errors would otherwise be retried with backoff
+        let client = super::HttpClient::with_options(&super::HttpOptions {
+            retries: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        let size = client
+            .download_file(&format!("{}/ok", base), out)
+            .await
+            .unwrap();
+        assert_eq!(size, 10);
+
+        // Error pages are not saved as the segment
+        tokio::fs::remove_file(out).await.unwrap();
+        for path in ["missing", "error"] {
+            assert!(client
+                .download_file(&format!("{}/{}", base, path), out)
+                .await
+                .is_err());
+            assert!(!std::path::Path::new(out).exists());
+        }
+
+        server.abort();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetch_range() {
+        use axum::{http::StatusCode, routing::get, Router};
+
+        let app = Router::new()
+            .route(
+                "/partial",
+                get(|| async { (StatusCode::PARTIAL_CONTENT, "2345") }),
+            )
+            .route("/full", get(|| async { "0123456789" }))
+            .route(
+                "/truncated",
+                get(|| async { (StatusCode::PARTIAL_CONTENT, "23") }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = super::HttpClient::new().unwrap();
+        let data = client
+            .fetch_range(&format!("{}/partial", base), 2, 5)
+            .await
+            .unwrap();
+        assert_eq!(data, b"2345");
+
+        // A server that ignores the range sends the whole body
+        assert!(matches!(
+            client.fetch_range(&format!("{}/full", base), 2, 5).await,
+            Err(super::DownloadError::NotPartialContent { status, .. }) if status == 200
+        ));
+        assert!(matches!(
+            client
+                .fetch_range(&format!("{}/truncated", base), 2, 5)
+                .await,
+            Err(super::DownloadError::WrongLength {
+                expected: 4,
+                actual: 2,
+                ..
+            })
+        ));
+
+        server.abort();
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use futures::{stream, StreamExt, TryStreamExt};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio_retry::{
+    strategy::{jitter, ExponentialBackoff},
+    Retry,
+};
+
+use crate::{
+    info,
+    player_response::{AdaptiveFormat, InitialPlayerResponse},
+    source::{FormatSelection, Track},
+    util, worker,
+};
+
+/// Size of each ranged request. YouTube throttles requests for large ranges,
+/// so formats are fetched in chunks.
+const CHUNK_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How often a chunk is fetched again if the response is not the requested
+/// range, such as a full or truncated body
+const CHUNK_RETRIES: usize = 3;
+
+#[derive(thiserror::Error, Debug)]
+pub enum VodError {
+    #[error("No {0} format with a URL found")]
+    MissingFormat(&'static str),
+    #[error("I/O error")]
+    IoError(#[from] std::io::Error),
+    #[error("Download error")]
+    DownloadError(#[from] util::DownloadError),
+}
+
+/// Files written by [`download`]
+pub struct VodFiles {
+    pub video: PathBuf,
+    pub audio: PathBuf,
+    pub info: info::InfoJson,
+}
+
+fn select_format<'a>(
+    ipr: &'a InitialPlayerResponse,
+    kind: &'static str,
+    format: &FormatSelection,
+) -> Result<&'a AdaptiveFormat, VodError> {
+    let candidates = ipr
+        .streaming_data
+        .as_ref()
+        .ok_or(VodError::MissingFormat(kind))?
+        .adaptive_formats
+        .iter()
+        .filter(|f| f.url.is_some() && f.mime().is_some_and(|m| m.kind == kind));
+
+    format
+        .select(candidates, |f| Some(f.itag), |f| f.height, |f| f.bitrate)
+        .ok_or(VodError::MissingFormat(kind))
+}
+
+fn format_fname(name: &str, format: &AdaptiveFormat) -> String {
+    let container = format.mime().map(|m| m.container);
+    let ext = match container.as_deref() {
+        Some("mp4") if format.is_audio() => "m4a",
+        Some(c) => c,
+        None => "mp4",
+    };
+    format!("{}.f{}.{}", name, format.itag, ext)
+}
+
+/// Downloads a format into `path` with up to `concurrency` ranged requests at
+/// a time, falling back to a single request if the size is unknown. Returns
+/// the number of bytes downloaded.
+pub async fn download_format(
+    client: &util::HttpClient,
+    format: &AdaptiveFormat,
+    path: &Path,
+    concurrency: usize,
+) -> Result<u64, VodError> {
+    let url = format
+        .url
+        .as_deref()
+        .ok_or(VodError::MissingFormat("any"))?;
+
+    let len = match format.content_length {
+        Some(len) => len,
+        None => {
+            let size = client.download_file(url, &path.to_string_lossy()).await?;
+            return Ok(size as u64);
+        }
+    };
+
+    let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let file = tokio::fs::File::create(&temp_path).await?;
+    file.set_len(len).await?;
+    drop(file);
+
+    let ranges = (0..len)
+        .step_by(CHUNK_SIZE as usize)
+        .map(|start| (start, (start + CHUNK_SIZE).min(len) - 1));
+
+    stream::iter(ranges)
+        .map(|(start, end)| {
+            let temp_path = &temp_path;
+            async move {
+                let retries = ExponentialBackoff::from_millis(500)
+                    .map(jitter)
+                    .take(CHUNK_RETRIES);
+                let data = Retry::start(retries, || async {
+                    let res = client.fetch_range(url, start, end).await;
+                    if let Err(e) = &res {
+                        warn!(
+                            "Could not download bytes {}-{} of f{}: {}",
+                            start, end, format.itag, e
+                        );
+                    }
+                    res
+                })
+                .await?;
+                let mut file = tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .open(temp_path)
+                    .await?;
+                file.seek(SeekFrom::Start(start)).await?;
+                file.write_all(&data).await?;
+                file.flush().await?;
+                debug!("Downloaded bytes {}-{} of f{}", start, end, format.itag);
+                Ok::<_, VodError>(())
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect::<()>()
+        .await?;
+
+    tokio::fs::rename(&temp_path, path).await?;
+
+    Ok(len)
+}
+
+/// Downloads the selected audio and video formats of a finished video into
+/// `workdir`, along with the thumbnail and an `info.json` metadata sidecar.
+/// The format and concurrency are taken from the worker options.
+pub async fn download(
+    client: &util::HttpClient,
+    ipr: &InitialPlayerResponse,
+    workdir: &Path,
+    config: &worker::WorkerConfig,
+) -> Result<VodFiles, VodError> {
+    let format = &config.format;
+    let mut ipr = ipr.clone();
+    if let Err(e) = ipr.decipher_formats(client, &util::cache_dir()).await {
+        warn!(
+            "Could not decipher format URLs, downloads may be throttled: {}",
+            e
+        );
+    }
+    let ipr = &ipr;
+
+    let video = select_format(ipr, "video", format)?;
+    let audio = select_format(ipr, "audio", format)?;
+
+    let mut info = info::InfoJson::from_ipr(ipr);
+    info.set_formats(&[Track::from(audio), Track::from(video)]);
+    let info_path = workdir.join("info.json");
+    info.write(&info_path).await?;
+
+    let (video_path, audio_path) = (
+        workdir.join(format_fname("video", video)),
+        workdir.join(format_fname("audio", audio)),
+    );
+    if let Err(e) = worker::thumbnail_dl(client, ipr, workdir, &mut Default::default()).await {
+        warn!("Could not download thumbnail: {}", e);
+    }
+
+    if config.storyboard {
+        worker::storyboard_dl(client, ipr, workdir, config.concurrency).await;
+    }
+
+    info!("Downloading f{} and f{}", video.itag, audio.itag);
+    let (video_size, audio_size) = futures::try_join!(
+        download_format(client, video, &video_path, config.concurrency),
+        download_format(client, audio, &audio_path, config.concurrency),
+    )?;
+
+    info.bytes_downloaded = video_size + audio_size;
+    info.actual_end_time = info
+        .player_response
+        .microformat
+        .as_ref()
+        .and_then(|m| {
+            m.player_microformat_renderer
+                .live_broadcast_details
+                .as_ref()
+        })
+        .and_then(|d| d.end_timestamp.as_deref())
+        .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.with_timezone(&chrono::Utc));
+    info.write(&info_path).await?;
+
+    Ok(VodFiles {
+        video: video_path,
+        audio: audio_path,
+        info,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_formats() {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/watchpage_post_live.html");
+        let html = std::fs::read_to_string(d).expect("Could not read test file");
+        let ipr = InitialPlayerResponse::from_html(&html).expect("Could not parse IPR");
+
+        let video = select_format(&ipr, "video", &FormatSelection::Best).expect("No video format");
+        assert_eq!(video.itag, 298);
+        assert_eq!(format_fname("video", video), "video.f298.mp4");
+
+        let audio = select_format(&ipr, "audio", &FormatSelection::Best).expect("No audio format");
+        assert_eq!(audio.itag, 251);
+        assert_eq!(format_fname("audio", audio), "audio.f251.webm");
+    }
+
+    #[test]
+    fn select_formats_by_preference() {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/watchpage_post_live.html");
+        let html = std::fs::read_to_string(d).expect("Could not read test file");
+        let ipr = InitialPlayerResponse::from_html(&html).expect("Could not parse IPR");
+        let itag = |kind, format: &str| {
+            select_format(&ipr, kind, &format.parse().unwrap()).map(|f| f.itag)
+        };
+
+        assert_eq!(itag("video", "worst").ok(), Some(160));
+        assert_eq!(itag("audio", "worst").ok(), Some(140));
+        assert_eq!(itag("video", "360p").ok(), Some(134));
+        assert_eq!(itag("video", "299+136,140").ok(), Some(136));
+        assert_eq!(itag("audio", "299+136,140").ok(), Some(140));
+        assert!(itag("video", "299").is_err());
+
+        assert_eq!("720P".parse(), Ok(FormatSelection::MaxHeight(720)));
+        assert!("highest".parse::<FormatSelection>().is_err());
+    }
+}
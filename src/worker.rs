@@ -1,209 +1,512 @@
 use futures::{join, stream::FuturesOrdered, try_join, StreamExt};
-use std::{path::Path, sync::Arc};
+use std::{
+    convert::Infallible,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tokio::{select, sync::RwLock};
 use tokio_retry::Retry;
+use tokio_util::sync::CancellationToken;
 
-use crate::{dash, hls, player_response, util};
+use crate::{
+    event::{self, EndReason, EventKind, EventSender},
+    ffmpeg, hls, info, player_response,
+    source::{self, FormatSelection, Source, SourceKind},
+    storyboard, util,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum WorkerError {
     #[error("Error getting initial player response")]
     InitialPlayerResponseError(#[from] player_response::PlayerResponseError),
-    #[error("Could not find representation")]
-    MissingRepresentation(String),
+    #[error("Could not get segments")]
+    SourceError(#[from] source::SourceError),
     #[error("I/O error")]
     IoError(#[from] std::io::Error),
     #[error("Download error")]
     DownloadError(#[from] util::DownloadError),
     #[error("No thumbnail found")]
     NoThumbnail,
+    #[error("Could not convert thumbnail")]
+    ThumbnailConvertError(#[from] ffmpeg::FfmpegError),
 }
 
+/// How often the watch page is re-fetched to pick up title and description
+/// edits, and to check whether the stream is still live
+const IPR_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the thumbnail is re-downloaded, as it is often changed after
+/// the stream goes live
+const THUMBNAIL_REFRESH_INTERVAL: Duration = Duration::from_secs(600);
+
+/// How polling the manifest for new segments is retried
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub attempts: usize,
+    /// Delay before the first retry, doubled for every following one
+    #[serde(deserialize_with = "util::deserialize_secs")]
+    pub initial_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            initial_delay: Duration::from_millis(200),
+        }
+    }
+}
+
+impl RetryConfig {
+    fn strategy(&self) -> impl Iterator<Item = Duration> + Clone {
+        let initial_delay = self.initial_delay;
+        (0..self.attempts as u32)
+            .map(move |i| initial_delay.saturating_mul(2u32.saturating_pow(i)))
+            .map(tokio_retry::strategy::jitter)
+    }
+}
+
+/// Options for downloading a stream. Durations are given in seconds when
+/// deserialized.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// Manifest to download segments from, if available
+    pub source: SourceKind,
+    pub format: FormatSelection,
+    /// Number of segments, or chunks of each format of a finished video,
+    /// downloaded at the same time
+    pub concurrency: usize,
+    /// How often the manifest is checked for new segments. Streams with
+    /// short segments need a shorter interval to stay close to live.
+    #[serde(deserialize_with = "util::deserialize_secs")]
+    pub poll_interval: Duration,
+    /// The download stops if no new segment appears for this long
+    #[serde(deserialize_with = "util::deserialize_secs")]
+    pub no_segment_timeout: Duration,
+    pub retry: RetryConfig,
+    /// Name of the master playlist in the working directory
+    pub playlist: String,
+    /// Directory inside the working directory that segments are written to,
+    /// instead of the working directory itself
+    #[serde(deserialize_with = "util::deserialize_subpath")]
+    pub segment_dir: Option<PathBuf>,
+    /// Download the storyboard sprite sheets, for seek previews
+    pub storyboard: bool,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            source: SourceKind::Dash,
+            format: FormatSelection::Best,
+            concurrency: 4,
+            poll_interval: Duration::from_secs(1),
+            no_segment_timeout: Duration::from_secs(30),
+            retry: RetryConfig::default(),
+            playlist: "index.m3u8".to_string(),
+            segment_dir: None,
+            storyboard: false,
+        }
+    }
+}
+
+/// State shared by the threads of a worker
+struct Context<'a> {
+    client: &'a util::HttpClient,
+    workdir: &'a Path,
+    config: &'a WorkerConfig,
+    info: RwLock<info::InfoJson>,
+    stats: RwLock<crate::stats::DownloadStatistics>,
+    cancel: CancellationToken,
+    events: Option<&'a EventSender>,
+    video_id: String,
+    /// Whether segments are added to those of a previous download
+    resume: bool,
+}
+
+impl Context<'_> {
+    fn emit(&self, kind: EventKind) {
+        event::emit(self.events, &self.video_id, kind);
+    }
+}
+
+/// Downloads the stream into `workdir`, returning the final metadata.
+///
+/// When `cancel` is triggered, no new segments are started. Segments that
+/// are already downloading are finished and the playlists are closed, so
+/// that what was captured so far can be muxed.
+///
+/// Progress is published to `events`, if given.
 pub async fn start(
     client: &util::HttpClient,
     ipr: &player_response::InitialPlayerResponse,
     workdir: &Path,
-) -> Result<(), WorkerError> {
-    let (manifest, thumbnail) = join!(
-        ipr.get_dash_representations(&client),
-        thumbnail_dl(&client, &ipr, workdir),
+    config: &WorkerConfig,
+    cancel: CancellationToken,
+    events: Option<&EventSender>,
+) -> Result<info::InfoJson, WorkerError> {
+    let video_id = ipr.video_id().unwrap_or_default().to_string();
+    let mut thumbnails = ThumbnailVersions::default();
+    let (source, thumbnail) = join!(
+        Source::new(client, ipr, config.source, &config.format),
+        thumbnail_dl(client, ipr, workdir, &mut thumbnails),
     );
 
-    let manifest = manifest?;
+    let source = source?;
     if let Err(e) = thumbnail {
         warn!("Could not download thumbnail: {}", e);
     }
 
-    let stats = Arc::new(RwLock::new(crate::stats::DownloadStatistics::new()));
+    // Write the metadata sidecar, continuing a previous download of the same
+    // formats into this working directory if there is one
+    let info_path = workdir.join("info.json");
+    let mut info = info::InfoJson::from_ipr(ipr);
+    info.set_formats(&source.tracks);
+    let resume = match info::InfoJson::read(&info_path).await {
+        Ok(mut previous)
+            if previous.id == info.id
+                && previous.format_id == info.format_id
+                && previous.last_segment.is_some() =>
+        {
+            info!(
+                "Resuming download after segment {}",
+                previous.last_segment.unwrap_or_default()
+            );
+            previous.update_from_ipr(ipr);
+            previous.actual_end_time = None;
+            info = previous;
+            true
+        }
+        _ => false,
+    };
+    info.write(&info_path).await?;
+
+    // Every error after this point is published as a `Failed` event
+    for track in &source.tracks {
+        info!("{}", track);
+        event::emit(events, &video_id, EventKind::FormatSelected(track.clone()));
+    }
+
+    let ctx = Context {
+        client,
+        workdir,
+        config,
+        info: RwLock::new(info),
+        stats: RwLock::new(crate::stats::DownloadStatistics::new(
+            &source.tracks,
+            source.segment_duration,
+        )),
+        cancel,
+        events,
+        video_id,
+        resume,
+    };
     let (tx_seq, rx_seq) = tokio::sync::mpsc::unbounded_channel();
 
-    try_join!(
-        thread_seq(&client, stats.clone(), tx_seq, &ipr),
-        thread_download(&client, stats.clone(), rx_seq, &manifest, workdir, 4),
-    )?;
+    let download = async {
+        try_join!(
+            thread_seq(&ctx, tx_seq, source.clone()),
+            thread_download(&ctx, rx_seq, &source),
+        )
+    };
+
+    // The thumbnail thread cannot finish by itself, so it is dropped once
+    // the download is done
+    let res = select! {
+        res = download => res,
+        never = thread_thumbnail(&ctx, thumbnails) => match never {},
+    };
+    if let Err(e) = res {
+        ctx.emit(EventKind::Failed {
+            error: e.to_string(),
+        });
+        return Err(e);
+    }
 
-    Ok(())
+    // Live storyboards keep growing, so they are fetched once it ends
+    if config.storyboard {
+        storyboard_dl(client, ipr, workdir, config.concurrency).await;
+    }
+
+    // Update the metadata sidecar with the final state
+    let (segments_downloaded, bytes_downloaded) = {
+        let st = ctx.stats.read().await;
+        (st.segments_downloaded, st.bytes_downloaded)
+    };
+    ctx.emit(EventKind::Finished {
+        segments_downloaded,
+        bytes_downloaded,
+    });
+    let mut info = ctx.info.into_inner();
+    info.actual_end_time = Some(chrono::Utc::now());
+    info.bytes_downloaded += bytes_downloaded;
+    info.write(&info_path).await?;
+
+    Ok(info)
 }
 
-async fn thumbnail_dl(
+/// Downloads the sprite sheets of the best storyboard level. Failures are
+/// only logged, as the download itself is unaffected.
+pub(crate) async fn storyboard_dl(
     client: &util::HttpClient,
     ipr: &player_response::InitialPlayerResponse,
     workdir: &Path,
-) -> Result<(), WorkerError> {
-    let url = || -> Option<String> {
-        Some(
-            ipr.microformat
-                .as_ref()?
-                .player_microformat_renderer
-                .thumbnail
-                .thumbnails
-                .last()?
-                .url
-                .clone(),
-        )
-    }()
-    .ok_or(WorkerError::NoThumbnail)?;
+    concurrency: usize,
+) {
+    let Some(level) = ipr.storyboard().and_then(|sb| sb.best_level().cloned()) else {
+        warn!("No storyboard found");
+        return;
+    };
+    match storyboard::download_sheets(client, &level, workdir, concurrency).await {
+        Ok(paths) => info!("Downloaded {} storyboard sheets", paths.len()),
+        Err(e) => warn!("Could not download storyboard: {}", e),
+    }
+}
 
-    let fname = workdir.join("thumbnail.jpg");
-    let fname = fname.to_string_lossy();
+/// Previously saved thumbnails, so that only changed images are kept
+#[derive(Default)]
+pub(crate) struct ThumbnailVersions {
+    last: Option<Vec<u8>>,
+    count: usize,
+}
 
-    client
-        .download_file(&url, &fname)
-        .await
-        .map_err(WorkerError::DownloadError)?;
+/// Thumbnail URLs to try, best first. `maxresdefault` is not always listed
+/// in the player response, so it is tried before the listed thumbnails.
+fn thumbnail_candidates(ipr: &player_response::InitialPlayerResponse) -> Vec<String> {
+    let mut urls = Vec::new();
+
+    if let Some(details) = ipr.video_details.as_ref() {
+        let suffix = if details.is_live { "_live" } else { "" };
+        urls.push(format!(
+            "https://i.ytimg.com/vi/{}/maxresdefault{}.jpg",
+            details.video_id, suffix
+        ));
+    }
 
-    info!("Thumbnail saved to {}", fname);
+    if let Some(mf) = ipr.microformat.as_ref() {
+        let mut thumbnails = mf
+            .player_microformat_renderer
+            .thumbnail
+            .thumbnails
+            .iter()
+            .collect::<Vec<_>>();
+        thumbnails.sort_by_key(|t| {
+            std::cmp::Reverse(t.width.unwrap_or_default() * t.height.unwrap_or_default())
+        });
+        urls.extend(thumbnails.into_iter().map(|t| t.url.clone()));
+    }
 
-    Ok(())
+    urls.dedup();
+    urls
 }
 
-async fn thread_seq(
+/// Downloads the best available thumbnail. Each distinct image is kept as
+/// `thumbnail.<n>.<ext>`, and the latest one is also saved as
+/// `thumbnail.jpg`, converting it if needed. Returns whether a new version
+/// was saved.
+pub(crate) async fn thumbnail_dl(
     client: &util::HttpClient,
-    stats: Arc<RwLock<crate::stats::DownloadStatistics>>,
-    tx_seq: tokio::sync::mpsc::UnboundedSender<i64>,
     ipr: &player_response::InitialPlayerResponse,
+    workdir: &Path,
+    versions: &mut ThumbnailVersions,
+) -> Result<bool, WorkerError> {
+    let mut image = None;
+    for url in thumbnail_candidates(ipr) {
+        match client.fetch_bytes(&url).await {
+            Ok(res) => {
+                image = Some(res);
+                break;
+            }
+            Err(e) => debug!("Could not fetch thumbnail {}: {}", url, e),
+        }
+    }
+    let (data, content_type) = image.ok_or(WorkerError::NoThumbnail)?;
+
+    if versions.last.as_ref() == Some(&data) {
+        return Ok(false);
+    }
+
+    let ext =
+        util::image_extension(content_type.as_deref(), &data).ok_or(WorkerError::NoThumbnail)?;
+    versions.count += 1;
+    let version_path = workdir.join(format!("thumbnail.{}.{}", versions.count, ext));
+    tokio::fs::write(&version_path, &data).await?;
+
+    let fname = workdir.join("thumbnail.jpg");
+    if ext == "jpg" {
+        tokio::fs::copy(&version_path, &fname).await?;
+    } else {
+        ffmpeg::convert_image(&version_path, &fname).await?;
+    }
+    versions.last = Some(data);
+
+    info!("Thumbnail saved to {}", version_path.display());
+
+    Ok(true)
+}
+
+/// Refreshes the thumbnail periodically until dropped.
+async fn thread_thumbnail(ctx: &Context<'_>, mut versions: ThumbnailVersions) -> Infallible {
+    loop {
+        tokio::time::sleep(THUMBNAIL_REFRESH_INTERVAL).await;
+
+        let ipr = ctx.info.read().await.player_response.clone();
+        if let Err(e) = thumbnail_dl(ctx.client, &ipr, ctx.workdir, &mut versions).await {
+            warn!("Could not download thumbnail: {}", e);
+        }
+    }
+}
+
+async fn thread_seq(
+    ctx: &Context<'_>,
+    tx_seq: tokio::sync::mpsc::UnboundedSender<source::Segment>,
+    mut source: Source,
 ) -> Result<(), WorkerError> {
-    let mut seq = 0;
+    let (client, config) = (ctx.client, ctx.config);
+    // A resumed download continues after the last downloaded segment
+    let mut seq = ctx.info.read().await.last_segment.map_or(0, |s| s + 1);
     let mut last_seq_time = std::time::Instant::now();
+    let mut last_refresh_time = std::time::Instant::now();
+    let mut ipr = ctx.info.read().await.player_response.clone();
 
-    let retry_strategy = tokio_retry::strategy::ExponentialBackoff::from_millis(200)
-        .map(tokio_retry::strategy::jitter)
-        .take(5);
-
-    'out: loop {
-        let manifest = Retry::spawn(retry_strategy.clone(), || {
-            ipr.get_dash_representations(&client)
-        })
-        .await
-        .map_err(WorkerError::InitialPlayerResponseError)?;
-
-        if manifest.latest_segment_number > seq {
-            for s in seq..manifest.latest_segment_number {
-                if seq > 0 {
-                    last_seq_time = std::time::Instant::now();
-                }
-                if tx_seq.send(s).is_err() {
-                    error!("Failed to send segment number to download thread");
-                    break 'out;
+    let retry_strategy = config.retry.strategy();
+
+    let reason = 'out: loop {
+        let poll = Retry::start(retry_strategy.clone(), || source.poll(client, &ipr, seq));
+        let segments = select! {
+            segments = poll => segments.map_err(WorkerError::SourceError)?,
+            _ = ctx.cancel.cancelled() => break Some(EndReason::Cancelled),
+        };
+
+        for segment in segments {
+            if seq > 0 {
+                last_seq_time = std::time::Instant::now();
+            }
+            let s = segment.seq;
+
+            // Segments that are no longer available, e.g. after a restart
+            if seq > 0 && s > seq {
+                warn!("Segments {} to {} are no longer available", seq, s - 1);
+                let mut info = ctx.info.write().await;
+                (seq..s).for_each(|missing| info.add_gap(missing));
+                if let Some(gap) = info.gaps.last() {
+                    ctx.emit(EventKind::Gap(gap.clone()));
                 }
+            }
+
+            if tx_seq.send(segment).is_err() {
+                error!("Failed to send segment number to download thread");
+                break 'out None;
+            }
+
+            ctx.stats.write().await.segments_total = 1 + s as u64;
+            seq = s + 1;
+        }
+
+        if last_refresh_time.elapsed() > IPR_REFRESH_INTERVAL {
+            last_refresh_time = std::time::Instant::now();
+            match refresh_ipr(client, &ipr).await {
+                Ok(new_ipr) => {
+                    let mut info = ctx.info.write().await;
+                    if info.update_from_ipr(&new_ipr) {
+                        info.write(&ctx.workdir.join("info.json")).await?;
+                    }
+                    drop(info);
 
-                let mut st = stats.write().await;
-                st.segments_total = 1 + s as u64;
-                st.print();
+                    match source.refresh(client, &new_ipr).await {
+                        Ok(()) => ctx.emit(EventKind::UrlRefreshed),
+                        Err(e) => warn!("Could not refresh segment URLs: {}", e),
+                    }
+                    ipr = new_ipr;
+                }
+                Err(e) => warn!("Could not refresh player response: {}", e),
             }
-            seq = manifest.latest_segment_number;
         }
 
         if !ipr.is_usable() {
             info!("Video is no longer live");
-            break;
+            break Some(EndReason::NotLive);
         }
 
-        if last_seq_time.elapsed().as_secs() > 30 {
-            warn!("No new segments found for 30 seconds, stopping");
-            break;
+        if last_seq_time.elapsed() > config.no_segment_timeout {
+            warn!(
+                "No new segments found for {} seconds, stopping",
+                config.no_segment_timeout.as_secs_f32()
+            );
+            break Some(EndReason::Timeout);
         }
 
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-    }
+        select! {
+            _ = tokio::time::sleep(config.poll_interval) => (),
+            _ = ctx.cancel.cancelled() => break Some(EndReason::Cancelled),
+        }
+    };
 
+    if let Some(reason) = reason {
+        ctx.emit(EventKind::StreamEnded(reason));
+    }
     debug!("Sequence thread exited");
 
     Ok(())
 }
 
-async fn thread_download(
+async fn refresh_ipr(
     client: &util::HttpClient,
-    stats: Arc<RwLock<crate::stats::DownloadStatistics>>,
-    rx_seq: tokio::sync::mpsc::UnboundedReceiver<i64>,
-    manifest: &dash::Manifest,
-    workdir: &Path,
-    concurrency: usize,
+    ipr: &player_response::InitialPlayerResponse,
+) -> Result<player_response::InitialPlayerResponse, player_response::PlayerResponseError> {
+    let video_id = ipr
+        .video_id()
+        .ok_or(player_response::PlayerResponseError::NoInitialPlayerResponse)?;
+    player_response::InitialPlayerResponse::fetch(client, video_id).await
+}
+
+async fn thread_download(
+    ctx: &Context<'_>,
+    rx_seq: tokio::sync::mpsc::UnboundedReceiver<source::Segment>,
+    source: &Source,
 ) -> Result<(), WorkerError> {
-    // Get the highest quality audio
-    let mut audio = manifest
-        .representations
-        .iter()
-        .filter(|r| r.height.is_none())
-        .collect::<Vec<_>>();
-    audio.sort_by(|a, b| a.bandwidth.cmp(&b.bandwidth));
-    let audio = *audio
-        .last()
-        .ok_or(WorkerError::MissingRepresentation("audio".to_string()))?;
-
-    // Get the highest quality video
-    let mut video = manifest
-        .representations
-        .iter()
-        .filter(|r| r.height.is_some())
-        .collect::<Vec<_>>();
-    video.sort_by(|a, b| a.bandwidth.cmp(&b.bandwidth));
-    let video = *video
-        .last()
-        .ok_or(WorkerError::MissingRepresentation("video".to_string()))?;
-
-    info!(
-        "Video: {}x{} {}fps ({}, f{})",
-        video.width.ok_or(WorkerError::MissingRepresentation(
-            "video width".to_string()
-        ))?,
-        video.height.ok_or(WorkerError::MissingRepresentation(
-            "video height".to_string()
-        ))?,
-        video.frame_rate.ok_or(WorkerError::MissingRepresentation(
-            "video frame rate".to_string()
-        ))?,
-        video.codecs,
-        video.id,
-    );
-    info!(
-        "Audio: {}kbps ({}, f{})",
-        audio.bandwidth / 1000,
-        audio.codecs,
-        audio.id
-    );
+    let (client, workdir, config) = (ctx.client, ctx.workdir, ctx.config);
+    let tracks = &source.tracks;
+    let concurrency = config.concurrency.max(1);
+
+    // Segments are referenced relative to the playlists in the workdir
+    let segment_dir = config.segment_dir.as_deref().unwrap_or(Path::new(""));
+    let outdir = workdir.join(segment_dir);
+    tokio::fs::create_dir_all(&outdir).await?;
+    let outdir = outdir.as_path();
 
     // Write the m3u8 file
-    let segment_duration = std::time::Duration::from_millis(manifest.segment_duration as u64);
-    let playlist_path = workdir.join("index.m3u8");
-    let mut playlist =
-        hls::IndexPlaylist::new(&playlist_path.to_string_lossy(), &manifest, &audio, &video)
-            .await
-            .map_err(WorkerError::IoError)?;
+    let playlist_path = workdir.join(&config.playlist);
+    let playlist_path = playlist_path.to_string_lossy();
+    let playlist = if ctx.resume {
+        hls::IndexPlaylist::resume(&playlist_path, source.segment_duration, tracks).await
+    } else {
+        hls::IndexPlaylist::new(&playlist_path, source.segment_duration, tracks).await
+    };
+    let mut playlist = playlist.map_err(WorkerError::IoError)?;
 
     let mut tasks = FuturesOrdered::new();
     let mut seq_stream = tokio_stream::wrappers::UnboundedReceiverStream::new(rx_seq);
     let mut is_done = false;
 
     loop {
+        // Segments that are not downloading yet are skipped when cancelled
+        if !is_done && ctx.cancel.is_cancelled() {
+            info!("Stopping, finishing {} segments in progress", tasks.len());
+            is_done = true;
+        }
+
         // Start new downloads if we have room
         while tasks.len() < concurrency && !is_done {
             select! {
-                seq = seq_stream.next() => {
-                    match seq {
-                        Some(seq) => tasks.push_back(util::download_av_segment(
-                            &client, workdir, &audio, &video, seq,
-                        )),
+                segment = seq_stream.next() => {
+                    match segment {
+                        Some(segment) => tasks.push_back(async move {
+                            let res = util::download_segment(client, outdir, tracks, &segment).await;
+                            (segment, res)
+                        }),
                         None => {
                             is_done = true;
                             break;
@@ -224,19 +527,50 @@ async fn thread_download(
 
         // Write finished segments to playlist file
         match tasks.next().await {
-            Some(Ok((fname_audio, fname_video, size_total))) => {
+            Some((segment, Ok((fnames, sizes)))) => {
+                let fnames = fnames
+                    .iter()
+                    .map(|f| segment_dir.join(f).to_string_lossy().into_owned())
+                    .collect::<Vec<_>>();
                 playlist
-                    .add_segment(&fname_audio, &fname_video, segment_duration)
+                    .add_segment(&fnames, segment.duration, segment.time)
                     .await
                     .map_err(WorkerError::IoError)?;
 
-                let mut st = stats.write().await;
-                st.segments_downloaded += 1;
-                st.bytes_downloaded += size_total as u64;
-                st.print();
+                let mut info = ctx.info.write().await;
+                info.first_segment.get_or_insert(segment.seq);
+                info.last_segment = Some(segment.seq);
+                drop(info);
+
+                let mut st = ctx.stats.write().await;
+                st.add_segment(segment.seq, &sizes);
+                let snapshot = st.snapshot();
+                drop(st);
+
+                ctx.emit(EventKind::SegmentDownloaded {
+                    seq: segment.seq,
+                    bytes: sizes.iter().sum(),
+                    duration: segment.duration,
+                });
+                ctx.emit(EventKind::Progress(snapshot));
             }
-            Some(Err(e)) => {
-                error!("Could not download segment: {}", e);
+            Some((segment, Err(e))) => {
+                error!("Could not download segment {}: {}", segment.seq, e);
+                ctx.emit(EventKind::SegmentFailed {
+                    seq: segment.seq,
+                    error: e.to_string(),
+                });
+
+                let mut info = ctx.info.write().await;
+                info.add_gap(segment.seq);
+                if let Some(gap) = info.gaps.last() {
+                    ctx.emit(EventKind::Gap(gap.clone()));
+                }
+                drop(info);
+
+                let mut st = ctx.stats.write().await;
+                st.add_failure();
+                ctx.emit(EventKind::Progress(st.snapshot()));
             }
             None => (),
         }
@@ -245,5 +579,15 @@ async fn thread_download(
     // Close the playlist
     playlist.finish().await.map_err(WorkerError::IoError)?;
 
+    // Remove partial files left by failed downloads
+    let mut entries = tokio::fs::read_dir(outdir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let fname = entry.file_name();
+        let fname = fname.to_string_lossy();
+        if fname.starts_with("seq_") && fname.ends_with(".tmp") {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+
     Ok(())
 }